@@ -1,26 +1,46 @@
 /*
-主要存放与命令行参数相关的函数，因为程序比较简单，
-就直接用标准库里的东西了，没有引用额外的包
+主要存放与命令行参数相关的函数，子命令越来越多，
+就改用clap来解析了，帮助信息也由它生成
 */
 
 use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::Instant;
 //终端彩色输出
-use crate::command;
-use crate::config;
+use crate::cache;
+use crate::command::{self, Verbosity};
+use crate::config::{self, Mode};
+use crate::coverage;
+use crate::diagnostic;
+use crate::error::{SmError, SmResult};
+use crate::events::{self, MessageFormat};
+use crate::report::Report;
+use crate::testing::{self, TestCase, TestResult};
+use crate::toolchain::Toolchain;
 use ansi_rgb::{green, red, Background};
+use clap::{Args, Parser, Subcommand};
 
 //定义project.toml标准模板
 const PROJECT_TXT: &str = r#"
+#Use [[target]] arrays to describe several targets in one project,
+#each with its own kind(static, shared, exe, header) and deps(e.g. ["core"])
 [target]
 name="demo"
 #Include file directory
 inc="inc"
 #Source file 
 src="src"
+#Glob patterns used instead of src, and files to skip(e.g. ["src/legacy/**"])
+#sources=["src/**/*.cpp"]
+#exclude=[]
+#Source file extensions
+#extensions=["cpp", "cxx", "cc", "c++", "c"]
 #Cpp file that contained main function
 entrance="main.cpp"
 #Supported Library type:static(sta),dynamic(dyn)
@@ -29,11 +49,22 @@ mode="sta"
 lib="lib"
 #Execute output directory
 bin="bin"
+#Settings used only by this target
+#defines=["LEVEL=2"] or defines={LEVEL=2, USE_FOO=""}
+#include_dirs=[]
+#system_include_dirs=[]
+#lib_dirs=[]
+#link_args=[]
+#Settings also used by the targets depending on this one
+#[target.public]
+#defines=[]
+#include_dirs=[]
 
 [complier]
-#Supported C++ complier:g++ clang++
+#C++ complier name or path(e.g. g++-13, /opt/llvm/bin/clang++, "ccache g++"),
+#detected automatically when omitted, the CXX environment variable overrides it
 cxx="g++"
-#Supported C++ standard:98 11 14 17 20
+#Supported C++ standard:98 11 14 17 20 23 26, GNU dialects like "gnu++20" as well
 std=11
 #enable Wall mode
 wall=true
@@ -43,173 +74,609 @@ ol=1
 link=[]
 #Adding more arguments
 extra=[]
+#C complier for .c files, defaults to the one matching cxx, CC overrides it
+#cc="gcc"
+#Supported C standard:89 99 11 17 23
+#c_std=11
+#Adding more arguments for .c files only
+#c_extra=[]
+#Archiver for static libraries, defaults to ar, AR overrides it
+#ar="ar"
+#Compiler cache: a wrapper like "ccache" or "sccache", or "sm" for the built-in one
+#cache="sm"
+#Directory of the built-in cache, shared between checkouts, defaults to ~/.cache/sm
+#cache_dir=""
+
+#Settings used by "sm build --profile release", debug and custom profiles work the same way
+#[profile.release]
+#ol=3
+#debug=false
+#defines=["NDEBUG"]
+#sanitizers=[]
+#extra=[]
+
+#Cross compiling settings used by "sm build --toolchain aarch64",
+#a separate file with the same keys works via "--toolchain file.toml"
+#[toolchain.aarch64]
+#target="aarch64-linux-gnu"
+#cxx="aarch64-linux-gnu-g++"
+#ar="aarch64-linux-gnu-ar"
+#sysroot=""
+#flags=[]
+#link_flags=[]
+
+#Tests run by "sm test", each linked against the library targets,
+#every source file in the tests directory is a test when there is no [[test]]
+#[[test]]
+#name="test_add"
+#sources=["tests/test_add.cpp"]
+#deps=[]
+#args=[]
+#"sm coverage" rebuilds the tests with --coverage(GCC) or
+#-fprofile-instr-generate -fcoverage-mapping(Clang), no need to edit extra
 "#;
 
-//读取命令行，并根据输入依次调用相应的子函数
-pub fn read_console_input() {
-    //收集命令行参数
-    let args: Vec<String> = env::args().collect();
-    //如果为空，直接打印帮助信息
-    if args.len() < 2 {
-        print_help_infomation();
-        return;
-    }
-    let command = &args[1];
-    //获取当前目录
-    let mut current_path = std::env::current_dir().unwrap();
+//命令行定义，帮助信息由clap自动生成
+#[derive(Parser)]
+#[command(name = "sm", version, about = "A Cpp make tool wirtten in rust.")]
+struct Cli {
+    //project.toml的路径，默认为当前目录下的project.toml
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Path to project.toml, defaults to ./project.toml"
+    )]
+    manifest_path: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        conflicts_with = "quiet",
+        help = "Printing up-to-date steps as well"
+    )]
+    verbose: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Printing only compiler messages and errors"
+    )]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    #[command(about = "Creating a brand new project.")]
+    New {
+        #[arg(help = "Name of the project directory")]
+        name: String,
+    },
+    #[command(about = "Initializing a existed project.")]
+    Init,
+    #[command(about = "Building the project.")]
+    Build(BuildArgs),
+    #[command(about = "Writing compile_commands.json without building.")]
+    Compdb(BuildArgs),
+    #[command(about = "Building it, and running it.")]
+    Run {
+        #[command(flatten)]
+        build: BuildArgs,
+        //--后面的参数原样传给程序
+        #[arg(last = true, help = "Arguments passed to the program")]
+        args: Vec<String>,
+    },
+    #[command(about = "Building the tests, and running them.")]
+    Test(TestArgs),
+    #[command(about = "Running the tests with coverage instrumentation, and reporting it.")]
+    Coverage(TestArgs),
+    #[command(about = "Clean up the project(deleting the bin, obj).")]
+    Clean,
+    #[command(about = "Managing the built-in object cache.")]
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    #[command(about = "Printing the size and hit rate of the cache.")]
+    Stats,
+    #[command(about = "Deleting everything in the cache.")]
+    Clear,
+}
+
+//build和run共用的选项
+#[derive(Args)]
+struct BuildArgs {
+    #[arg(help = "Target to build, together with the targets it depends on")]
+    target: Option<String>,
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Number of jobs to run at once, defaults to the number of CPUs"
+    )]
+    jobs: Option<u32>,
+    #[arg(
+        short,
+        long,
+        help = "Compiling every independent file even if some of them fail"
+    )]
+    keep_going: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Using the debug, release or a custom [profile.NAME] settings"
+    )]
+    profile: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME|FILE",
+        help = "Cross compiling with a [toolchain.NAME] section or a toolchain file"
+    )]
+    toolchain: Option<String>,
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        help = "Writing compiler diagnostics to a sarif or json file"
+    )]
+    diagnostics_format: Option<diagnostic::Format>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "diagnostics_format",
+        help = "Path of the diagnostics file, defaults to .sm/diagnostics.<FORMAT>"
+    )]
+    diagnostics_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "human",
+        help = "Printing colored text, or newline-delimited JSON events for tools"
+    )]
+    message_format: MessageFormat,
+}
+
+//test的选项
+#[derive(Args)]
+struct TestArgs {
+    #[arg(help = "Running only the tests whose names contain one of these")]
+    filters: Vec<String>,
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Number of jobs and tests to run at once, defaults to the number of CPUs"
+    )]
+    jobs: Option<u32>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Using the debug, release or a custom [profile.NAME] settings"
+    )]
+    profile: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME|FILE",
+        help = "Cross compiling with a [toolchain.NAME] section or a toolchain file"
+    )]
+    toolchain: Option<String>,
+    #[arg(
+        long,
+        value_name = "FORMAT[=PATH]",
+        help = "Writing the results as junit or tap, e.g. junit=results.xml"
+    )]
+    report: Vec<String>,
+}
+
+//读取命令行，并根据输入依次调用相应的子函数，返回进程的退出码
+pub fn read_console_input() -> SmResult<i32> {
+    let cli = Cli::parse();
+    //--message-format json时只输出事件，错误信息也不带颜色
+    if let Commands::Build(build) | Commands::Compdb(build) | Commands::Run { build, .. } =
+        &cli.command
+    {
+        events::set_format(build.message_format);
+    }
+    let verbosity = if cli.quiet || events::is_json() {
+        Verbosity::Quiet
+    } else if cli.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
     //判断命令参数
-    match command.as_str() {
-        "new" => {
-            //需要判断是否有第二个参数，即项目名
-            if args.len() < 3 {
-                println!("{}", "Need project name!".bg(red()));
-                return;
-            }
-            let project_name = &args[2];
+    match &cli.command {
+        Commands::New { name } => {
             //调用新建项目函数
-            create_new_project(project_name);
+            create_new_project(name)?;
+        }
+        Commands::Init => {
+            init_existed_project()?;
+        }
+        Commands::Build(build) => {
+            let con = load_project(&cli, build.toolchain.as_deref(), build.profile.as_deref())?;
+            build_project(&con, build, verbosity)?;
         }
-        "init" => {
-            init_existed_project();
+        Commands::Compdb(build) => {
+            let con = load_project(&cli, build.toolchain.as_deref(), build.profile.as_deref())?;
+            //编辑器需要所有目标的记录，这里忽略指定的目标
+            let ac = command::AllCommand::new(&con, None, verbosity)?;
+            let path = ac.write_compdb()?;
+            if verbosity > Verbosity::Quiet {
+                println!("Writing {} successfully.", path.display());
+            }
+        }
+        Commands::Run { build, args } => {
+            let con = load_project(&cli, build.toolchain.as_deref(), build.profile.as_deref())?;
+            //没有指定目标时运行第一个可执行文件
+            let target = match &build.target {
+                Some(name) => con.find_target(name),
+                None => con.default_binary(),
+            };
+            let target = match target {
+                Some(t) if t.has_binary() => t,
+                _ => return Err(SmError::Usage("No executable target to run!".to_string())),
+            };
+            //先做一次增量构建，源代码没有变化时很快就能结束
+            let build = BuildArgs {
+                target: Some(target.name.clone()),
+                jobs: build.jobs,
+                keep_going: build.keep_going,
+                profile: build.profile.clone(),
+                toolchain: build.toolchain.clone(),
+                diagnostics_format: build.diagnostics_format,
+                diagnostics_file: build.diagnostics_file.clone(),
+                message_format: build.message_format,
+            };
+            build_project(&con, &build, verbosity)?;
+            let lib_path = library_path(&con, &con.resolve(Some(&target.name))?)?;
+            //加载可执行目录，标准输入输出直接继承，程序的输出实时显示
+            let bin_file = con.root.join(&target.bin).join(&target.name);
+            let status = Command::new(&bin_file)
+                .args(args)
+                .env("LD_LIBRARY_PATH", lib_path)
+                .status()
+                .map_err(|e| SmError::io(&bin_file, e))?;
+            //把程序的退出码原样返回
+            return Ok(exit_code(status));
         }
-        "help" => {
-            print_help_infomation();
+        Commands::Test(test) => {
+            let con = load_project(&cli, test.toolchain.as_deref(), test.profile.as_deref())?;
+            return test_status(&run_tests(&con, test, verbosity)?);
         }
-        "build" => {
-            current_path.push("project.toml");
-            //读取配置文件
-            let con = config::Project::new(&current_path);
-            let mut ac = command::AllCommand::new(&con);
-            ac.run();
+        Commands::Coverage(test) => {
+            let mut con = load_project(&cli, test.toolchain.as_deref(), test.profile.as_deref())?;
+            return run_coverage(&mut con, test, verbosity);
         }
-        "run" => {
-            /*
-                这里日后需要大改，需要判断源代码改变来判断是否需要重新编译再运行
-            */
-            current_path.push("project.toml");
-            //读取配置文件
-            let con = config::Project::new(&current_path);
-            current_path.pop();
-            //加载可执行目录
-            current_path.push(con.target.bin);
-            current_path.push(con.target.name);
-            let output = Command::new(current_path).output().expect(
-                format!("Excuting {} Failed", "binary")
-                    .bg(red())
-                    .to_string()
-                    .as_str(),
-            );
-            //检测命令是否成功执行
-            if output.status.success() {
-                let stdout = String::from_utf8_lossy(&output.stdout);
-                //打印出来
-                println!("{}", stdout);
+        Commands::Cache { action } => {
+            //在项目里时使用project.toml里设置的缓存目录
+            let manifest = match &cli.manifest_path {
+                Some(p) => p.clone(),
+                None => current_dir()?.join("project.toml"),
+            };
+            let dir = if manifest.is_file() {
+                load_project(&cli, None, None)?.cache_dir()
             } else {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                println!("{}", stderr.bg(red()));
+                cache::default_dir()
+            };
+            match action {
+                CacheAction::Stats => {
+                    let (count, size) = cache::usage(&dir);
+                    let stats = cache::load_stats(&dir);
+                    let total = stats.hits + stats.misses;
+                    println!("Cache directory: {}", dir.display());
+                    println!("Objects: {}", count);
+                    println!("Size: {}", human_size(size));
+                    println!("Hits: {}", stats.hits);
+                    println!("Misses: {}", stats.misses);
+                    if total > 0 {
+                        println!("Hit rate: {:.1}%", stats.hits as f64 * 100.0 / total as f64);
+                    }
+                }
+                CacheAction::Clear => {
+                    cache::clear(&dir)?;
+                    println!(
+                        "Clearing {} successfully.",
+                        dir.display().to_string().bg(green())
+                    );
+                }
             }
         }
-        "clean" => {
-            current_path.push("project.toml");
-            //读取配置文件
-            let con = config::Project::new(&current_path);
-            current_path.pop();
+        Commands::Clean => {
+            let con = load_project(&cli, None, None)?;
             //删除.sm bin下面的所有文件
-            current_path.push(".sm");
-            fs::remove_dir_all(&current_path).expect(".sm does not exist, ignoring it.");
-            current_path.pop();
-            current_path.push(&con.target.bin);
-            fs::remove_dir_all(&current_path)
-                .expect(format!("{} does not exist, ignoring it.", &con.target.bin).as_str());
-            // current_path.pop();
-            // current_path.push(&con.target.lib);
-            // fs::remove_dir_all(&current_path)
-            //     .expect(format!("{} does not exist, ignoring it.", &con.target.lib).as_str());
+            remove_dir(&con.root.join(".sm"))?;
+            //多个目标可能共用同一个bin目录
+            let mut bins: Vec<&String> = con.targets().iter().map(|t| &t.bin).collect();
+            bins.sort();
+            bins.dedup();
+            for bin in bins {
+                remove_dir(&con.root.join(bin))?;
+            }
         }
-        _ => {
-            println!("{}", "Unspported arguments!".bg(red()));
-            print_help_infomation();
+    }
+    Ok(0)
+}
+
+//构建指定的目标，没有指定时构建所有目标
+fn build_project(con: &config::Project, build: &BuildArgs, verbosity: Verbosity) -> SmResult<()> {
+    //可以指定只构建某个目标以及它依赖的目标
+    let mut ac = command::AllCommand::new(con, build.target.as_deref(), verbosity)?;
+    //-j指定同时编译的任务数，默认为CPU核数
+    if let Some(jobs) = build.jobs {
+        ac.set_jobs(jobs as usize);
+    }
+    ac.set_keep_going(build.keep_going);
+    //--diagnostics-format把编译器的诊断信息写入文件，给代码审查工具使用
+    if let Some(format) = build.diagnostics_format {
+        let path = match &build.diagnostics_file {
+            Some(p) => p.clone(),
+            None => con
+                .sm_dir()
+                .join(format!("diagnostics.{}", format.extension())),
+        };
+        ac.set_report(format, path);
+    }
+    //每次构建都同步更新compile_commands.json，编辑器看到的参数和实际执行的一致，
+    //只构建一部分目标时另外求出所有目标的编译命令，免得其他目标的记录丢失
+    match build.target {
+        Some(_) => command::AllCommand::new(con, None, Verbosity::Quiet)?.write_compdb()?,
+        None => ac.write_compdb()?,
+    };
+    ac.run()
+}
+
+//构建项目和所有测试，然后运行名字匹配的测试
+fn run_tests(
+    con: &config::Project,
+    test: &TestArgs,
+    verbosity: Verbosity,
+) -> SmResult<Vec<TestResult>> {
+    let tests = con.tests()?;
+    if tests.is_empty() {
+        return Err(SmError::Usage(
+            "No tests found, add [[test]] entries or a tests directory".to_string(),
+        ));
+    }
+    //先检查报告的格式，免得构建完了才报错
+    let reports: Vec<Report> = test
+        .report
+        .iter()
+        .map(|r| Report::parse(r, &con.sm_dir()))
+        .collect::<SmResult<_>>()?;
+    //只构建测试依赖的目标，和测试无关的可执行文件编译不过也不影响测试
+    let (mut ac, binaries) = command::AllCommand::for_tests(con, &tests, verbosity)?;
+    if let Some(jobs) = test.jobs {
+        ac.set_jobs(jobs as usize);
+    }
+    ac.run()?;
+    //不写过滤条件时运行所有测试
+    let cases: Vec<TestCase> = tests
+        .iter()
+        .zip(binaries)
+        .filter(|(t, _)| test.filters.is_empty() || test.filters.iter().any(|f| t.name.contains(f)))
+        .map(|(t, binary)| TestCase {
+            name: t.name.clone(),
+            binary,
+            args: t.args.clone(),
+        })
+        .collect();
+    let filtered = tests.len() - cases.len();
+    let lib_path = library_path(con, &con.resolve(None)?)?;
+    let start = Instant::now();
+    let jobs = test.jobs.map_or_else(command::default_jobs, |j| j as usize);
+    let results = testing::run_tests(&cases, jobs, &lib_path, verbosity);
+    for report in &reports {
+        report.write(&results)?;
+        if verbosity > Verbosity::Quiet {
+            println!("Writing {} successfully.", report.path.display());
+        }
+    }
+    let failed: Vec<&TestResult> = results.iter().filter(|r| !r.passed).collect();
+    if !failed.is_empty() {
+        println!("failures:");
+        for r in &failed {
+            println!("    {}", r.name);
+            //认出了测试框架时，列出失败的用例
+            for c in r.parsed.iter().flat_map(|p| &p.cases).filter(|c| !c.passed) {
+                println!("        {}", c.name);
+            }
         }
     }
+    let status = if failed.is_empty() {
+        "ok".bg(green())
+    } else {
+        "FAILED".bg(red())
+    };
+    println!(
+        "test result: {}. {} passed; {} failed; {} filtered out; finished in {:.2}s",
+        status,
+        results.len() - failed.len(),
+        failed.len(),
+        filtered,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(results)
 }
 
-//打印帮助信息
-fn print_help_infomation() {
-    //定义帮助信息
-    let help_infomation = r#"
-    sm new [project_name]   Creating a brand new project.
-    sm init                 Initializing a existed project.
-    sm build                Building the project.
-    sm run                  Building it, and running it.
-    sm clean                Clean up the project(deleting the bin, obj).
-    sm help                 Printing the help infomation.
-    "#;
-    println!("{}", help_infomation);
+//有测试失败时返回Build错误
+fn test_status(results: &[TestResult]) -> SmResult<i32> {
+    match results.iter().filter(|r| !r.passed).count() {
+        0 => Ok(0),
+        n => Err(SmError::Build(format!("{} tests failed", n))),
+    }
+}
+
+//带着插桩参数重新构建并运行测试，测试失败时也生成覆盖率报告
+fn run_coverage(con: &mut config::Project, test: &TestArgs, verbosity: Verbosity) -> SmResult<i32> {
+    let toolchain = Toolchain::detect(&con.complier, con.cross.as_ref(), &con.sm_dir())?;
+    con.apply_coverage(coverage::flags(toolchain.family)?);
+    let sm_dir = con.sm_dir();
+    coverage::prepare(&toolchain, &sm_dir)?;
+    let results = run_tests(con, test, verbosity)?;
+    //Clang需要知道哪些程序和动态库里有插桩数据
+    let mut objects: Vec<PathBuf> = Vec::new();
+    for t in con.tests()? {
+        let target = con.test_target(&t);
+        objects.push(con.root.join(&target.bin).join(&target.name));
+    }
+    for t in con.targets() {
+        if t.get_mode() == Mode::Dynamic {
+            objects.push(con.root.join(&t.lib).join(format!("lib{}.so", t.name)));
+        }
+    }
+    //只统计项目里的源文件和头文件，测试代码和第三方头文件不算
+    let dirs: Vec<PathBuf> = con.targets().iter().flat_map(|t| t.source_dirs()).collect();
+    let data = coverage::collect(&toolchain, &sm_dir, &objects)?.restrict(&con.root, &dirs);
+    //报告放在.sm/coverage里，和插桩的中间文件在一起
+    data.write_lcov(&sm_dir.join("lcov.info"), &con.root)?;
+    data.write_html(&sm_dir.join("html"), &con.root)?;
+    if verbosity > Verbosity::Quiet {
+        println!();
+        data.print_summary();
+        println!(
+            "Writing {} successfully.",
+            sm_dir.join("html").join("index.html").display()
+        );
+    }
+    test_status(&results)
+}
+
+//运行程序时的LD_LIBRARY_PATH，动态库所在的目录加到前面
+fn library_path(con: &config::Project, targets: &[&config::Target]) -> SmResult<OsString> {
+    let mut lib_dirs: Vec<PathBuf> = Vec::new();
+    for t in targets {
+        let dir = con.root.join(&t.lib);
+        if t.get_mode() == Mode::Dynamic && !lib_dirs.contains(&dir) {
+            lib_dirs.push(dir);
+        }
+    }
+    if let Some(old) = env::var_os("LD_LIBRARY_PATH") {
+        lib_dirs.extend(env::split_paths(&old));
+    }
+    env::join_paths(lib_dirs)
+        .map_err(|e| SmError::Usage(format!("Invalid library directory: {}", e)))
+}
+
+//把字节数转换成方便阅读的大小
+fn human_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut value = size as f64;
+    let mut i = 0;
+    while value >= 1024.0 && i < units.len() - 1 {
+        value /= 1024.0;
+        i += 1;
+    }
+    format!("{:.1} {}", value, units[i])
+}
+
+//求出子进程的退出码，被信号杀死时按照shell的习惯返回128加信号值
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+//读取配置文件并切换工具链和profile，之后的命令都在项目根目录下执行
+fn load_project(
+    cli: &Cli,
+    toolchain: Option<&str>,
+    profile: Option<&str>,
+) -> SmResult<config::Project> {
+    let path = match &cli.manifest_path {
+        Some(p) => p.clone(),
+        None => current_dir()?.join("project.toml"),
+    };
+    let mut con = config::Project::new(&path)?;
+    //--toolchain切换交叉编译工具链，要在profile之前，产物放在lib/<toolchain>/<profile>
+    if let Some(toolchain) = toolchain {
+        con.apply_toolchain(toolchain)?;
+    }
+    //--profile切换编译设置
+    if let Some(profile) = profile {
+        con.apply_profile(profile)?;
+    }
+    env::set_current_dir(&con.root).map_err(|e| SmError::io(&con.root, e))?;
+    Ok(con)
+}
+
+//获取当前目录
+fn current_dir() -> SmResult<PathBuf> {
+    env::current_dir().map_err(|e| SmError::io(Path::new("."), e))
+}
+
+//删除文件夹，不存在时忽略
+fn remove_dir(p: &Path) -> SmResult<()> {
+    match fs::remove_dir_all(p) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("{} does not exist, ignoring it.", p.display());
+            Ok(())
+        }
+        Err(e) => Err(SmError::io(p, e)),
+    }
 }
 
 //新建一个项目
-fn create_new_project(project_name: &String) {
+fn create_new_project(project_name: &String) -> SmResult<()> {
     //按道理是要先检查项目名是否正确的，但是先不管了
     //首先获取当前目录
-    let mut current_path = env::current_dir().expect("Can't get current dir!");
+    let mut current_path = current_dir()?;
     //拼接
     current_path.push(project_name);
-    match fs::create_dir(&current_path) {
-        Ok(_) => {
-            println!(
-                "Creating {} successfully, adding more directory...",
-                current_path
-                    .clone()
-                    .as_os_str()
-                    .to_str()
-                    .unwrap()
-                    .bg(green())
-            );
-            //创建include文件夹
-            current_path.push("inc");
-            fs::create_dir(&current_path).unwrap();
-            println!("Creating {} directory successfully.", "include".bg(green()));
-            //回退
-            current_path.pop();
-            //创建source文件夹
-            current_path.push("src");
-            fs::create_dir(&current_path).unwrap();
-            println!("Creating {} directory successfully.", "source".bg(green()));
-            //回退
-            current_path.pop();
-            //创建lib文件夹
-            current_path.push("lib");
-            fs::create_dir(&current_path).unwrap();
-            println!("Creating {} directory successfully.", "lib".bg(green()));
-            //回退
-            current_path.pop();
-            //创建bin文件夹
-            current_path.push("bin");
-            fs::create_dir(&current_path).unwrap();
-            println!("Creating {} directory successfully.", "bin".bg(green()));
-            //回退
-            current_path.pop();
-            //写入project.toml文件
-            current_path.push("project.toml");
-            let mut file = File::create(current_path).unwrap();
-            file.write_all(PROJECT_TXT.as_bytes()).unwrap();
-            println!("Creating {} successfully.", "project.toml".bg(green()));
-            println!("{}", "All done, enjoying yourself!");
-        }
-        Err(e) => {
-            println!("Error:{}", e);
-        }
+    fs::create_dir(&current_path).map_err(|e| SmError::io(&current_path, e))?;
+    println!(
+        "Creating {} successfully, adding more directory...",
+        current_path.display().to_string().bg(green())
+    );
+    //依次创建include、source、lib、bin文件夹
+    for (dir, name) in [
+        ("inc", "include"),
+        ("src", "source"),
+        ("lib", "lib"),
+        ("bin", "bin"),
+    ] {
+        current_path.push(dir);
+        fs::create_dir(&current_path).map_err(|e| SmError::io(&current_path, e))?;
+        println!("Creating {} directory successfully.", name.bg(green()));
+        //回退
+        current_path.pop();
     }
+    //写入project.toml文件
+    current_path.push("project.toml");
+    write_template(&current_path)?;
+    println!("Creating {} successfully.", "project.toml".bg(green()));
+    println!("All done, enjoying yourself!");
+    Ok(())
 }
 
 //初始化一个已经存在的项目
-fn init_existed_project() {
+fn init_existed_project() -> SmResult<()> {
     //首先获取当前目录
-    let mut current_path = env::current_dir().expect("Can't get current dir!");
+    let mut current_path = current_dir()?;
     //拼接
     current_path.push("project.toml");
     //写入
-    let mut file = File::create(current_path).unwrap();
-    file.write_all(PROJECT_TXT.as_bytes()).unwrap();
+    write_template(&current_path)?;
     println!("Creating {} successfully.", "project.toml".bg(green()));
+    Ok(())
+}
+
+//写入project.toml模板
+fn write_template(p: &Path) -> SmResult<()> {
+    let mut file = File::create(p).map_err(|e| SmError::io(p, e))?;
+    file.write_all(PROJECT_TXT.as_bytes())
+        .map_err(|e| SmError::io(p, e))
 }
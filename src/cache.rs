@@ -0,0 +1,250 @@
+/*
+内置的目标文件缓存，键为预处理之后的源文件加上完整的编译参数的摘要，
+缓存目录可以在多个项目副本之间共用，命中时直接复制目标文件，不再编译，
+编译器当时输出的警告也一起保存，命中时原样返回
+*/
+
+use crate::command::OneLineCommand;
+use crate::error::{SmError, SmResult};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//缓存的编译器输出里代替项目根目录的占位符
+const ROOT_MARK: &str = "<SM_ROOT>/";
+
+pub struct ObjectCache {
+    dir: PathBuf,
+    //编译器的身份，换了编译器之后不会用到旧的缓存
+    salt: String,
+    //项目根目录，参数里的路径换成相对路径，不同位置的副本也能命中
+    root: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+//命中次数的统计，保存在缓存目录的stats.toml里
+#[derive(Serialize, Deserialize, Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl ObjectCache {
+    pub fn new(dir: PathBuf, salt: String, root: PathBuf) -> ObjectCache {
+        ObjectCache {
+            dir,
+            salt,
+            root,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+    //执行编译命令，先按预处理的结果查找缓存，没有时编译并把结果放入缓存
+    pub fn compile(&self, cmd: &OneLineCommand, object: &Path) -> (Option<i32>, String) {
+        let key = match self.key(cmd) {
+            Some(k) => k,
+            //预处理失败时直接编译，让编译器报告错误
+            None => return cmd.output(),
+        };
+        let cached = self.object_path(&key);
+        let cached_output = cached.with_extension("txt");
+        if let Ok(text) = fs::read_to_string(&cached_output) {
+            if fs::copy(&cached, object).is_ok() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return (Some(0), text.replace(ROOT_MARK, &self.root_prefix()));
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let (code, text) = cmd.output();
+        if code == Some(0) {
+            //先写输出再写目标文件，有目标文件时输出一定已经在了
+            //输出里的项目路径换成占位符，其他位置的副本命中时换回它自己的路径
+            let portable = text.replace(&self.root_prefix(), ROOT_MARK);
+            let stored = self.store(&key, portable.as_bytes(), &cached_output)
+                && fs::read(object).is_ok_and(|o| self.store(&key, &o, &cached));
+            if !stored {
+                let _ = fs::remove_file(&cached_output);
+            }
+        }
+        (code, text)
+    }
+    //先写到临时文件再改名，其他进程不会读到一半的文件
+    fn store(&self, key: &str, content: &[u8], path: &Path) -> bool {
+        let tmp = self
+            .dir
+            .join("tmp")
+            .join(format!("{}.{}", key, process::id()));
+        let stored = path.parent().map_or(Ok(()), fs::create_dir_all).is_ok()
+            && tmp.parent().map_or(Ok(()), fs::create_dir_all).is_ok()
+            && fs::write(&tmp, content).is_ok()
+            && fs::rename(&tmp, path).is_ok();
+        if !stored {
+            let _ = fs::remove_file(&tmp);
+        }
+        stored
+    }
+    //计算缓存的键，预处理时也会生成依赖文件，命中缓存时依赖信息同样是最新的
+    fn key(&self, cmd: &OneLineCommand) -> Option<String> {
+        let mut pre = OneLineCommand::new(cmd.program());
+        let mut flags: Vec<String> = Vec::new();
+        let mut args = cmd.arguments().iter();
+        while let Some(a) = args.next() {
+            //保留行号标记，注释的行数不同时调试信息里的行号也不同
+            if a == "-c" {
+                pre.arg("-E");
+                continue;
+            }
+            if a == "-o" {
+                args.next();
+                pre.args(["-o", "-"]);
+                continue;
+            }
+            if a == "-MF" {
+                //依赖文件的路径不影响编译结果
+                if let Some(d) = args.next() {
+                    pre.arg("-MF").arg(d);
+                }
+                continue;
+            }
+            pre.arg(a);
+            flags.push(self.normalize(a));
+        }
+        let preprocessed = pre.stdout_bytes()?;
+        let mut context = md5::Context::new();
+        context.consume(self.salt.as_bytes());
+        context.consume(flags.join("\n").as_bytes());
+        context.consume(b"\n");
+        context.consume(self.strip_root(&preprocessed));
+        Some(format!("{:x}", context.compute()))
+    }
+    fn root_prefix(&self) -> String {
+        format!("{}/", self.root.display())
+    }
+    //行号标记里项目内的绝对路径换成相对路径，比如 # 1 "/home/a/demo/src/a.cpp"
+    fn strip_root(&self, preprocessed: &[u8]) -> Vec<u8> {
+        let prefix = format!("\"{}", self.root_prefix()).into_bytes();
+        let mut result: Vec<u8> = Vec::with_capacity(preprocessed.len());
+        for line in preprocessed.split_inclusive(|&b| b == b'\n') {
+            match line.windows(prefix.len()).position(|w| w == prefix) {
+                Some(i) if line.starts_with(b"# ") => {
+                    result.extend_from_slice(&line[..=i]);
+                    result.extend_from_slice(&line[i + prefix.len()..]);
+                }
+                _ => result.extend_from_slice(line),
+            }
+        }
+        result
+    }
+    //项目里的绝对路径换成相对路径
+    fn normalize(&self, arg: &OsString) -> String {
+        let p = Path::new(arg);
+        match p.strip_prefix(&self.root) {
+            Ok(rel) if p.is_absolute() => rel.to_string_lossy().into_owned(),
+            _ => arg.to_string_lossy().into_owned(),
+        }
+    }
+    fn object_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join("objects")
+            .join(&key[..2])
+            .join(format!("{}.o", key))
+    }
+    //把本次的命中次数累加到统计里
+    pub fn save_stats(&self) {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        if hits + misses == 0 {
+            return;
+        }
+        let mut stats = load_stats(&self.dir);
+        stats.hits += hits;
+        stats.misses += misses;
+        if let Ok(content) = toml::to_string(&stats) {
+            let _ = fs::create_dir_all(&self.dir);
+            let _ = fs::write(self.dir.join("stats.toml"), content);
+        }
+    }
+}
+
+//默认的缓存目录，SM_CACHE_DIR优先，其次是XDG_CACHE_HOME/sm和~/.cache/sm
+pub fn default_dir() -> PathBuf {
+    if let Some(d) = env::var_os("SM_CACHE_DIR").filter(|d| !d.is_empty()) {
+        return PathBuf::from(d);
+    }
+    if let Some(d) = env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(d).join("sm");
+    }
+    match env::var_os("HOME") {
+        Some(h) => PathBuf::from(h).join(".cache").join("sm"),
+        None => env::temp_dir().join("sm-cache"),
+    }
+}
+
+pub fn load_stats(dir: &Path) -> CacheStats {
+    fs::read_to_string(dir.join("stats.toml"))
+        .ok()
+        .and_then(|c| toml::from_str(&c).ok())
+        .unwrap_or_default()
+}
+
+//统计缓存里的目标文件个数和总大小
+pub fn usage(dir: &Path) -> (usize, u64) {
+    let mut count = 0;
+    let mut size = 0;
+    let buckets = match fs::read_dir(dir.join("objects")) {
+        Ok(b) => b,
+        Err(_) => return (0, 0),
+    };
+    for bucket in buckets.flatten() {
+        for entry in fs::read_dir(bucket.path()).into_iter().flatten().flatten() {
+            if let Ok(m) = entry.metadata() {
+                //.txt是编译器的输出，不算作目标文件
+                if entry.path().extension().is_some_and(|e| e == "o") {
+                    count += 1;
+                }
+                size += m.len();
+            }
+        }
+    }
+    (count, size)
+}
+
+//清空缓存，统计也一起清零
+pub fn clear(dir: &Path) -> SmResult<()> {
+    for sub in ["objects", "tmp"] {
+        let p = dir.join(sub);
+        if p.exists() {
+            fs::remove_dir_all(&p).map_err(|e| SmError::io(&p, e))?;
+        }
+    }
+    let stats = dir.join("stats.toml");
+    if stats.exists() {
+        fs::remove_file(&stats).map_err(|e| SmError::io(&stats, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_project_root_from_line_markers_only() {
+        let cache = ObjectCache::new(
+            PathBuf::from("/tmp/cache"),
+            String::new(),
+            PathBuf::from("/home/a/demo"),
+        );
+        let pre = b"# 1 \"/home/a/demo/src/a.cpp\"\n# 1 \"/usr/include/stdio.h\" 1 3 4\nconst char* p = \"/home/a/demo/x\";\n\n\n# 7 \"/home/a/demo/src/a.cpp\" 2\n";
+        let stripped = cache.strip_root(pre);
+        assert_eq!(
+            String::from_utf8(stripped).unwrap(),
+            "# 1 \"src/a.cpp\"\n# 1 \"/usr/include/stdio.h\" 1 3 4\nconst char* p = \"/home/a/demo/x\";\n\n\n# 7 \"src/a.cpp\" 2\n"
+        );
+    }
+}
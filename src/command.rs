@@ -2,400 +2,914 @@
 根据读取出来的配置文件，生成命令行语句
 */
 
-use crate::config::{Mode, Project};
-use ansi_rgb::{cyan_blue, red, Background};
+use crate::cache::ObjectCache;
+use crate::compdb::{self, CompileCommand};
+use crate::config::{self, Mode, Project, Target, Test};
+use crate::diagnostic::{self, Format};
+use crate::error::{SmError, SmResult};
+use crate::events;
+use crate::graph::{BuildGraph, Step, StepKind};
+use crate::probe;
+use crate::state::BuildState;
+use crate::toolchain::{Tool, Toolchain};
 use duct::cmd;
-use std::{fs, io, path::PathBuf};
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Instant,
+};
 
-//有限状态机
-#[derive(PartialEq)]
-enum State {
-    Start,
-    Failed,
-    Obj,
-    Lib,
-    Bin,
-    End,
-}
-
-struct OneLineCommand {
-    meta_data: String,
-    bin: String,
-    args: Vec<String>,
+//一条命令，程序和每个参数都单独保存，路径里有空格也不会被拆开
+pub struct OneLineCommand {
+    bin: OsString,
+    args: Vec<OsString>,
 }
 
 impl OneLineCommand {
-    fn new(data: String) -> OneLineCommand {
-        //分解成为程序和参数
-        let words: Vec<_> = data
-            .split_ascii_whitespace()
-            .map(|w| w.to_string())
-            .collect();
-        return OneLineCommand {
-            meta_data: data,
-            bin: words[0].clone(),
-            args: words[1..].to_vec(),
-        };
+    pub fn new<S: AsRef<OsStr>>(bin: S) -> OneLineCommand {
+        OneLineCommand {
+            bin: bin.as_ref().to_os_string(),
+            args: Vec::new(),
+        }
+    }
+    //追加一个参数
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut OneLineCommand {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+    //追加多个参数
+    pub fn args<I, S>(&mut self, args: I) -> &mut OneLineCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for a in args {
+            self.arg(a);
+        }
+        self
+    }
+    pub fn program(&self) -> &OsStr {
+        &self.bin
+    }
+    pub fn arguments(&self) -> &[OsString] {
+        &self.args
     }
-    //阻塞执行命令
-    fn execute(&self) -> bool {
-        match cmd(&self.bin, &self.args).run() {
+    //程序和参数组成的完整参数列表，不是UTF-8的部分会被替换掉
+    pub fn argv(&self) -> Vec<String> {
+        std::iter::once(&self.bin)
+            .chain(self.args.iter())
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+    //按照shell的规则加上引号，只用于显示和记录，不会拿去执行
+    pub fn display(&self) -> String {
+        self.argv()
+            .iter()
+            .map(|a| shell_quote(a))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    //执行命令并返回标准输出，失败时返回None
+    pub fn stdout_bytes(&self) -> Option<Vec<u8>> {
+        let output = cmd(&self.bin, &self.args)
+            .stdout_capture()
+            .stderr_null()
+            .unchecked()
+            .run()
+            .ok()?;
+        output.status.success().then_some(output.stdout)
+    }
+    //阻塞执行命令，并把输出收集起来，返回退出码以及需要打印的内容，
+    //没能启动或者被信号杀死时退出码为None
+    pub fn output(&self) -> (Option<i32>, String) {
+        let expr = cmd(&self.bin, &self.args)
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked();
+        match expr.run() {
             Ok(output) => {
-                //检测命令是否成功执行
-                if output.status.success() {
-                    let stdout = String::from_utf8_lossy(&output.stdout);
-                    //打印出来
-                    println!("{}", stdout);
-                    true
-                } else {
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    println!("{}", stderr.bg(red()));
-                    false
-                }
-            }
-            Err(e) => {
-                eprintln!("Excuting {} Failed: {}", self.meta_data, e);
-                false
+                //原样返回，由构建图解析诊断信息之后再上色，成功时的警告信息也要保留
+                let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+                (output.status.code(), text)
             }
+            Err(e) => (None, format!("Excuting {} Failed: {}\n", self.display(), e)),
         }
     }
 }
 
 //所有需要执行的命令
 pub struct AllCommand {
-    //命令需要细分，先编译目标文件，然后再打包成库，最后才是可执行文件
-    obj_cmds: Vec<OneLineCommand>,
-    lib_cmd: OneLineCommand,
-    bin_cmd: OneLineCommand,
-    state: State,
-    mode: Mode,
+    //命令需要细分，先编译目标文件，然后再打包成库，最后才是可执行文件，都放在构建图里
+    graph: BuildGraph,
+    //构建数据库及其路径
+    db: BuildState,
+    db_path: PathBuf,
+    //同时执行的最大任务数
+    jobs: usize,
+    //出错之后是否继续执行不受影响的步骤
+    keep_going: bool,
+    //项目根目录
+    root: PathBuf,
+    //输出的详细程度
+    verbosity: Verbosity,
+    //实际使用的编译器和打包工具
+    toolchain: Toolchain,
+    //包在编译命令外面的缓存程序，比如ccache
+    wrapper: Option<String>,
+    //内置的目标文件缓存
+    cache: Option<Arc<ObjectCache>>,
+    //存放目标文件的目录，下面每个目标一个子目录
+    obj_dir: PathBuf,
+    //本次构建的目标，以及配置文件里所有的目标，用来清理过期的目标文件
+    built: Vec<String>,
+    known: Vec<String>,
+    //测试的目标文件放在单独的目录里，tests为本次构建的测试，只构建目标时为None
+    test_obj_dir: PathBuf,
+    tests: Option<Vec<String>>,
+    //诊断信息报告的格式和路径
+    report: Option<(Format, PathBuf)>,
+    //自定义步骤生成的头文件，编译步骤要等它们生成之后才能执行
+    generated: Vec<PathBuf>,
+    //配置文件里所有目标的库和可执行文件的相对路径，没有构建的目标的记录不能清理
+    artifacts: Vec<String>,
+}
+
+//输出的详细程度，对应命令行的-q和-v
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    //只输出编译器的信息和错误
+    Quiet,
+    Normal,
+    //额外输出跳过的步骤
+    Verbose,
+}
+
+//一个目标的目标文件目录和构建数据库里键的前缀，测试和同名的目标互不影响
+struct Unit {
+    obj_dir: PathBuf,
+    key: String,
 }
 
+//测试在构建数据库里的键的前缀
+const TEST_KEY_PREFIX: &str = "test:";
+
 impl AllCommand {
-    pub fn new(project: &Project) -> AllCommand {
-        if !project.check_complier() {
-            panic!("C++ complier: {} is invaild!", project.complier.cxx);
-        }
-        if !project.check_ol() {
-            panic!("Opt {} is invaild!", project.complier.cxx);
+    //name为要构建的目标，为None时构建所有目标
+    pub fn new(
+        project: &Project,
+        name: Option<&str>,
+        verbosity: Verbosity,
+    ) -> SmResult<AllCommand> {
+        let mut all_command = AllCommand::setup(project, verbosity)?;
+        for target in project.resolve(name)? {
+            all_command.add_library_target(project, target)?;
         }
-        if !project.check_std() {
-            panic!("{} is invaild!", project.complier.cxx);
+        Ok(all_command)
+    }
+    //只构建测试和它们依赖的目标，返回命令和测试程序的路径
+    pub fn for_tests(
+        project: &Project,
+        tests: &[Test],
+        verbosity: Verbosity,
+    ) -> SmResult<(AllCommand, Vec<PathBuf>)> {
+        let mut all_command = AllCommand::setup(project, verbosity)?;
+        let binaries = all_command.add_tests(project, tests)?;
+        Ok((all_command, binaries))
+    }
+    //检查编译器并读取构建数据库，还没有添加任何步骤
+    fn setup(project: &Project, verbosity: Verbosity) -> SmResult<AllCommand> {
+        //配置项在读取project.toml时已经检查过了
+        let root = project.root.clone();
+        //0.创建存放中间文件的文件夹，检查编译器的结果也缓存在这里
+        fs::create_dir_all(project.sm_dir()).map_err(|e| SmError::io(&project.sm_dir(), e))?;
+        //确认编译器存在，并且支持要求的C++标准
+        let toolchain =
+            Toolchain::detect(&project.complier, project.cross.as_ref(), &project.sm_dir())?;
+        if verbosity == Verbosity::Verbose {
+            println!(
+                "Using {} ({} {}).",
+                toolchain.cxx,
+                toolchain.family.name(),
+                toolchain.version
+            );
         }
-        //这个构造函数实现很重要，需要慢慢写
         let mut all_command = AllCommand {
-            obj_cmds: Vec::<OneLineCommand>::new(),
-            lib_cmd: OneLineCommand::new("ls".to_string()),
-            bin_cmd: OneLineCommand::new("ls".to_string()),
-            state: State::Start,
-            mode: Mode::Invalid,
+            graph: BuildGraph::new(root.clone()),
+            db: BuildState::load(&project.sm_dir().join("state.toml")),
+            db_path: project.sm_dir().join("state.toml"),
+            jobs: default_jobs(),
+            keep_going: false,
+            root,
+            verbosity,
+            toolchain,
+            wrapper: None,
+            cache: None,
+            obj_dir: project.sm_dir().join("obj"),
+            built: Vec::new(),
+            known: project.targets().iter().map(|t| t.name.clone()).collect(),
+            test_obj_dir: project.sm_dir().join("tests").join("obj"),
+            tests: None,
+            report: None,
+            generated: Vec::new(),
+            artifacts: Vec::new(),
         };
-        //获取当前路径
-        let mut current_path = std::env::current_dir().unwrap();
-        //获取所有源文件
-        let src_files = project.get_src_files();
-        current_path.push(".sm");
-        //0.创建必要的文件夹
-        mkdir(&current_path);
-        current_path.pop();
-        current_path.push(&project.target.lib);
-        mkdir(&current_path);
-        current_path.pop();
-        current_path.push(&project.target.bin);
-        mkdir(&current_path);
-        current_path.pop();
-        all_command.mode = project.get_mode();
-        match all_command.mode {
-            //编译为静态库时
-            Mode::Static => {
-                //1.将所有源文件编译成目标文件
-                for (index, src_file) in src_files.iter().enumerate() {
-                    //后期实现应该计算文件的md5，看看是否有所改变，不能简单的用序号代表文件
-                    current_path.push(".sm");
-                    current_path.push(format!("{}.o", index));
-                    //类似于这种命令 g++ -std=c++11 -Wall -O2 -c file.cpp -o .sm/file1.o
-                    let mut cmd = format!(
-                        "{} -std=c++{} -O{} -c {} -o {} -I{}",
-                        project.complier.cxx,
-                        project.complier.std,
-                        project.complier.ol,
-                        src_file.to_str().unwrap(),
-                        current_path.to_str().unwrap(),
-                        project.target.inc,
-                    );
-                    //判断是否添加-Wall参数
-                    if project.complier.wall {
-                        cmd.push_str(" -Wall ");
-                    }
-                    //添加额外参数
-                    cmd.push_str(project.complier.extra.join(" ").as_str());
-                    //存入
-                    all_command.obj_cmds.push(OneLineCommand::new(cmd));
-                    current_path.pop();
-                    current_path.pop();
-                }
-                //2.收集所有目标文件
-                current_path.push(".sm");
-                let mut obj_files: Vec<String> = Vec::new();
-                for entry in fs::read_dir(&current_path).unwrap() {
-                    //如果是文件
-                    match entry {
-                        Ok(en) => {
-                            if en.path().is_file() {
-                                //记录文件路径
-                                let obj_file = en.path();
-                                //这里日后要修改，毕竟要支持多系统
-                                if obj_file.extension().unwrap() == "o" {
-                                    obj_files.push(obj_file.to_str().unwrap().to_string());
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                current_path.pop();
-                current_path.push(&project.target.lib);
-                //3.打包成静态库
-                let mut ar_cmd = format!(
-                    "ar rcs {}/lib{}.a ",
-                    current_path.to_str().unwrap(),
-                    project.target.name
-                );
-                for obj_file in obj_files {
-                    ar_cmd.push_str(&obj_file);
-                    ar_cmd.push(' ');
-                }
-                all_command.lib_cmd = OneLineCommand::new(ar_cmd);
-                current_path.pop();
-                current_path.push(&project.target.bin);
-                //4.编译二进制文件
-                let mut complie_cmd = format!(
-                    "{} -std=c++{} -O{} {} -o {}/{} -L{} -l{} -I{}",
-                    project.complier.cxx,
-                    project.complier.std,
-                    project.complier.ol,
-                    project.target.entrance,
-                    current_path.to_str().unwrap().to_string(),
-                    project.target.name,
-                    project.target.lib,
-                    project.target.name,
-                    project.target.inc,
+        all_command.artifacts = project
+            .targets()
+            .iter()
+            .flat_map(|t| {
+                [
+                    all_command.lib_file(t),
+                    all_command.root.join(&t.bin).join(&t.name),
+                ]
+            })
+            .map(|p| relative_to(&p, &all_command.root))
+            .collect();
+        //编译缓存，包装程序放在编译命令的前面，内置缓存在执行编译步骤时使用
+        match project.complier.cache.as_str() {
+            "" => {}
+            "sm" => {
+                let tc = &all_command.toolchain;
+                let salt = format!(
+                    "{} {} {} {}",
+                    probe::tool_identity(&tc.cxx),
+                    probe::tool_identity(&tc.cc),
+                    tc.family.name(),
+                    tc.version
                 );
-                //判断是否添加-Wall参数
-                if project.complier.wall {
-                    complie_cmd.push_str(" -Wall ");
-                }
-                //链接系统的静态库
-                for l in &project.complier.link {
-                    complie_cmd.push_str(format!(" -l{} ", l).as_str());
-                }
-                //添加额外的参数
-                complie_cmd.push_str(project.complier.extra.join(" ").as_str());
-                all_command.bin_cmd = OneLineCommand::new(complie_cmd);
+                all_command.cache = Some(Arc::new(ObjectCache::new(
+                    project.cache_dir(),
+                    salt,
+                    all_command.root.clone(),
+                )));
             }
-            Mode::Dynamic => {
-                let srcs: Vec<_> = src_files
-                    .iter()
-                    .map(|s| s.to_str().unwrap().to_string())
-                    .collect();
-                let srcs = srcs.join(" ");
-                current_path.push(&project.target.lib);
-                //1.源代码直接生成动态库文件
-                let mut lib_cmd = format!(
-                    "{} -shared -fPIC -std=c++{} -O{} {} -o {}/lib{}.so -I{}",
-                    project.complier.cxx,
-                    project.complier.std,
-                    project.complier.ol,
-                    srcs,
-                    current_path.to_str().unwrap().to_string(),
-                    project.target.name,
-                    project.target.inc,
-                );
-                current_path.pop();
-                //判断是否添加-Wall参数
-                if project.complier.wall {
-                    lib_cmd.push_str(" -Wall ");
+            w => {
+                if probe::find_program(w).is_none() {
+                    return Err(SmError::config(
+                        "complier.cache",
+                        format!("Can't find compiler cache {}", w),
+                    ));
                 }
-                current_path.push(&project.target.bin);
-                //添加额外的参数
-                lib_cmd.push_str(project.complier.extra.join(" ").as_str());
-                all_command.lib_cmd = OneLineCommand::new(lib_cmd);
-                //2.编译二进制文件
-                let mut complie_cmd = format!(
-                    "{} -std=c++{} -O{} {} -o {}/{} -L{} -l{} -I{}",
-                    project.complier.cxx,
-                    project.complier.std,
-                    project.complier.ol,
-                    project.target.entrance,
-                    current_path.to_str().unwrap().to_string(),
-                    project.target.name,
-                    project.target.lib,
-                    project.target.name,
-                    project.target.inc,
-                );
-                //判断是否添加-Wall参数
-                if project.complier.wall {
-                    complie_cmd.push_str(" -Wall ");
+                all_command.wrapper = Some(w.to_string());
+            }
+        }
+        all_command.add_custom_steps(project)?;
+        Ok(all_command)
+    }
+    //添加project.toml里的自定义步骤，每次构建都会检查它们是否需要执行
+    fn add_custom_steps(&mut self, project: &Project) -> SmResult<()> {
+        for s in &project.step {
+            let mut outputs: Vec<PathBuf> = s.outputs.iter().map(|o| self.root.join(o)).collect();
+            for o in &outputs {
+                if let Some(dir) = o.parent() {
+                    self.mkdir(dir)?;
                 }
-                //链接系统的静态库
-                for l in &project.complier.link {
-                    complie_cmd.push_str(format!(" -l{} ", l).as_str());
+            }
+            self.generated
+                .extend(outputs.iter().filter(|o| config::is_header(o)).cloned());
+            let mut cmd = OneLineCommand::new(&s.command[0]);
+            cmd.args(&s.command[1..]);
+            let output = outputs.remove(0);
+            let mut step = Step::new(
+                StepKind::Custom,
+                s.name.clone(),
+                cmd,
+                s.inputs.iter().map(|i| self.root.join(i)).collect(),
+                output,
+            );
+            step.extra_outputs = outputs;
+            self.graph.add(step)?;
+        }
+        Ok(())
+    }
+    //添加构建配置文件里的一个目标的步骤，已经添加过的跳过
+    fn add_library_target(&mut self, project: &Project, target: &Target) -> SmResult<()> {
+        if self.built.contains(&target.name) {
+            return Ok(());
+        }
+        //依赖链上的所有目标，被依赖的排在前面，最后一个是自己
+        let chain = project.resolve(Some(&target.name))?;
+        let unit = Unit {
+            obj_dir: self.obj_dir.join(&target.name),
+            key: target.name.clone(),
+        };
+        if self.add_target(project, target, &chain, &unit)? {
+            self.built.push(target.name.clone());
+        }
+        Ok(())
+    }
+    //添加构建测试程序的步骤，测试链接它依赖的目标，返回测试程序的路径
+    fn add_tests(&mut self, project: &Project, tests: &[Test]) -> SmResult<Vec<PathBuf>> {
+        let mut binaries: Vec<PathBuf> = Vec::new();
+        let mut names: Vec<String> = Vec::new();
+        for test in tests {
+            let target = project.test_target(test);
+            let mut chain: Vec<&Target> = Vec::new();
+            for d in &test.deps {
+                for t in project.resolve(Some(d))? {
+                    if !chain.iter().any(|c| c.name == t.name) {
+                        self.add_library_target(project, t)?;
+                        chain.push(t);
+                    }
                 }
-                //添加额外的参数
-                complie_cmd.push_str(project.complier.extra.join(" ").as_str());
-                all_command.bin_cmd = OneLineCommand::new(complie_cmd);
             }
+            chain.push(&target);
+            let unit = Unit {
+                obj_dir: self.test_obj_dir.join(&test.name),
+                key: format!("{}{}", TEST_KEY_PREFIX, test.name),
+            };
+            self.add_target(project, &target, &chain, &unit)?;
+            binaries.push(self.root.join(&target.bin).join(&target.name));
+            names.push(test.name.clone());
+        }
+        self.tests = Some(names);
+        Ok(binaries)
+    }
+    //添加构建一个目标需要的所有步骤，chain为它的依赖链，只有头文件的目标返回false
+    fn add_target(
+        &mut self,
+        project: &Project,
+        target: &Target,
+        chain: &[&Target],
+        unit: &Unit,
+    ) -> SmResult<bool> {
+        let mode = target.get_mode();
+        match mode {
             Mode::Invalid => {
-                panic!("Unsupported mode!");
+                return Err(SmError::config(
+                    "target.mode",
+                    format!("Target {} has an unsupported mode", target.name),
+                ))
             }
+            //只有头文件的目标不需要编译，只是把头文件目录提供给依赖它的目标
+            Mode::HeaderOnly => return Ok(false),
+            _ => {}
         }
-        all_command
-    }
-    pub fn run(&mut self) {
-        match self.mode {
+        //头文件目录，自己的排在前面
+        let mut flags: Vec<String> = Vec::new();
+        for t in chain.iter().rev() {
+            push_unique(&mut flags, vec![format!("-I{}", t.inc)]);
+        }
+        //自己的私有设置，加上依赖链上所有目标的公开设置
+        let private = target.private();
+        let mut usages = vec![&private];
+        usages.extend(chain.iter().rev().map(|t| &t.public));
+        let mut link_flags: Vec<String> = Vec::new();
+        for u in usages {
+            push_unique(&mut flags, u.compile_flags());
+            push_unique(&mut link_flags, u.link_flags());
+        }
+        //链接依赖的库，依赖别人的库要排在前面
+        let mut link_inputs: Vec<PathBuf> = Vec::new();
+        let mut link_args: Vec<OsString> = Vec::new();
+        for t in chain.iter().rev().skip(1) {
+            self.link_library(t, &mut link_inputs, &mut link_args);
+        }
+        //链接系统的库
+        let sys_libs: Vec<String> = project
+            .complier
+            .link
+            .iter()
+            .map(|l| format!("-l{}", l))
+            .collect();
+        //1.将所有源文件编译成目标文件，动态库需要位置无关代码
+        //入口文件单独编译成可执行文件的目标文件，就算在src里也不能算作库的源文件
+        let entrance = (!target.entrance.is_empty()).then(|| self.root.join(&target.entrance));
+        let mut obj_files: Vec<PathBuf> = Vec::new();
+        for src_file in target.get_src_files(&self.root)? {
+            if entrance.as_ref() == Some(&src_file) {
+                continue;
+            }
+            let obj = self.add_compile(project, unit, &src_file, &flags, mode == Mode::Dynamic)?;
+            obj_files.push(obj);
+        }
+        //2.把本次源文件对应的目标文件打包成静态库，或者链接成动态库
+        match mode {
             Mode::Static => {
-                self.sta_run();
+                self.mkdir(&self.root.join(&target.lib))?;
+                let lib_file = self.lib_file(target);
+                //每次都重新生成静态库，用q追加，不同目录下的同名目标文件不会互相覆盖
+                let mut ar_cmd = self.toolchain.ar.command();
+                ar_cmd.arg("qcs").arg(&lib_file).args(&obj_files);
+                self.graph.add(Step::new(
+                    StepKind::Archive,
+                    relative_to(&lib_file, &self.root),
+                    ar_cmd,
+                    obj_files.clone(),
+                    lib_file,
+                ))?;
             }
             Mode::Dynamic => {
-                self.dyn_run();
+                self.mkdir(&self.root.join(&target.lib))?;
+                let lib_file = self.lib_file(target);
+                let mut lib_cmd = self.toolchain.cxx.command();
+                lib_cmd
+                    .args(["-shared", "-fPIC"])
+                    .arg(format!("-O{}", project.complier.ol))
+                    .args(&obj_files)
+                    .arg("-o")
+                    .arg(&lib_file)
+                    .args(&link_args)
+                    .args(&link_flags)
+                    .args(&sys_libs)
+                    .args(&self.toolchain.flags)
+                    .args(&self.toolchain.link_flags)
+                    //额外参数里可能有链接时也需要的选项，比如-fsanitize
+                    .args(&project.complier.extra);
+                let mut inputs = obj_files.clone();
+                inputs.extend(link_inputs.iter().cloned());
+                self.graph.add(Step::new(
+                    StepKind::SharedLink,
+                    relative_to(&lib_file, &self.root),
+                    lib_cmd,
+                    inputs,
+                    lib_file,
+                ))?;
             }
-            Mode::Invalid => {
-                panic!("Unsupported mode!");
+            _ => {}
+        }
+        if !target.has_binary() {
+            return Ok(true);
+        }
+        //3.链接二进制文件，库目标的入口文件链接自己的库
+        self.mkdir(&self.root.join(&target.bin))?;
+        let mut inputs: Vec<PathBuf> = Vec::new();
+        if mode == Mode::Executable {
+            inputs.extend(obj_files.iter().cloned());
+        }
+        if let Some(entrance) = &entrance {
+            inputs.push(self.add_compile(project, unit, entrance, &flags, false)?);
+        }
+        let objs = inputs.clone();
+        //自己的库要排在所有依赖的前面
+        let mut args: Vec<OsString> = Vec::new();
+        if mode != Mode::Executable {
+            self.link_library(target, &mut inputs, &mut args);
+        }
+        args.extend(link_args);
+        args.extend(link_flags.into_iter().map(OsString::from));
+        args.extend(sys_libs.into_iter().map(OsString::from));
+        let bin_file = self.root.join(&target.bin).join(&target.name);
+        let mut complie_cmd = self.toolchain.cxx.command();
+        complie_cmd
+            .arg(&self.toolchain.std_flag)
+            .arg(format!("-O{}", project.complier.ol))
+            .args(&objs)
+            .arg("-o")
+            .arg(&bin_file)
+            .args(&args)
+            //交叉编译需要的参数
+            .args(&self.toolchain.flags)
+            .args(&self.toolchain.link_flags)
+            //添加额外的参数
+            .args(&project.complier.extra);
+        inputs.extend(link_inputs);
+        self.graph.add(Step::new(
+            StepKind::ExeLink,
+            relative_to(&bin_file, &self.root),
+            complie_cmd,
+            inputs,
+            bin_file,
+        ))?;
+        Ok(true)
+    }
+    //添加编译单个源文件的步骤，返回目标文件路径
+    fn add_compile(
+        &mut self,
+        project: &Project,
+        unit: &Unit,
+        src_file: &Path,
+        flags: &[String],
+        pic: bool,
+    ) -> SmResult<PathBuf> {
+        let src = relative_to(src_file, &self.root);
+        //目标文件的路径和源文件的相对路径一一对应，比如.sm/obj/demo/src/net/a.cpp.o
+        let mut stem = unit.obj_dir.clone();
+        stem.push(mirror_path(src_file, &self.root));
+        let obj_file = append_ext(&stem, "o");
+        let depfile = append_ext(&stem, "d");
+        if let Some(dir) = obj_file.parent() {
+            self.mkdir(dir)?;
+        }
+        //类似于这种命令 g++ -std=c++11 -O2 -c src/a.cpp -o .sm/obj/demo/src/a.cpp.o -Iinc -MMD -MF .sm/obj/demo/src/a.cpp.d -Wall
+        //.c文件用C编译器编译，其他的都按C++处理
+        let is_c = src_file.extension().is_some_and(|e| e == "c");
+        let mut cmd = if is_c {
+            let mut cmd = self.compile_command(&self.toolchain.cc);
+            if let Some(std) = project.complier.c_std {
+                cmd.arg(format!("-std=c{}", std));
             }
+            cmd
+        } else {
+            let mut cmd = self.compile_command(&self.toolchain.cxx);
+            cmd.arg(&self.toolchain.std_flag);
+            cmd
+        };
+        cmd.arg(format!("-O{}", project.complier.ol))
+            .arg("-c")
+            .arg(src_file)
+            .arg("-o")
+            .arg(&obj_file)
+            .args(flags)
+            .args(["-MMD", "-MF"])
+            .arg(&depfile)
+            .args(&self.toolchain.flags);
+        if pic {
+            cmd.arg("-fPIC");
+        }
+        //判断是否添加-Wall参数
+        if project.complier.wall {
+            cmd.arg("-Wall");
         }
+        //添加额外参数，每一项都是一个完整的参数
+        cmd.args(&project.complier.extra);
+        if is_c {
+            cmd.args(&project.complier.c_extra);
+        }
+        //第一个输入必须是源文件，后面是自定义步骤生成的头文件
+        let mut inputs = vec![src_file.to_path_buf()];
+        inputs.extend(self.generated.iter().cloned());
+        let mut step = Step::new(
+            StepKind::Compile,
+            format!("{}/{}", unit.key, src),
+            cmd,
+            inputs,
+            obj_file.clone(),
+        );
+        step.depfile = Some(depfile);
+        step.cache = self.cache.clone();
+        self.graph.add(step)?;
+        Ok(obj_file)
     }
-    fn sta_run(&mut self) {
-        let length = self.obj_cmds.len() + 2;
-        //FSM，有限状态机
-        loop {
-            match self.state {
-                //初始状态，需要编译源代码为目标文件，切换状态为obj
-                State::Start => {
-                    self.state = State::Obj;
-                }
-                //失败状态，输出提示信息，并切换状态为结束状态
-                State::Failed => {
-                    println!("{}", "Command aborting!".bg(red()));
-                    self.state = State::End;
-                }
-                State::End => {
-                    break;
+    //编译命令，设置了ccache这样的缓存程序时放在最前面
+    fn compile_command(&self, tool: &Tool) -> OneLineCommand {
+        match &self.wrapper {
+            Some(w) => {
+                let mut cmd = OneLineCommand::new(w);
+                cmd.arg(&tool.program).args(&tool.args);
+                cmd
+            }
+            None => tool.command(),
+        }
+    }
+    //库目标生成的文件
+    fn lib_file(&self, target: &Target) -> PathBuf {
+        let file = match target.get_mode() {
+            Mode::Dynamic => format!("lib{}.so", target.name),
+            _ => format!("lib{}.a", target.name),
+        };
+        self.root.join(&target.lib).join(file)
+    }
+    //添加链接一个库目标需要的输入和参数，静态库直接用路径，动态库用-L -l
+    fn link_library(&self, target: &Target, inputs: &mut Vec<PathBuf>, args: &mut Vec<OsString>) {
+        match target.get_mode() {
+            Mode::Static => {
+                let lib_file = self.lib_file(target);
+                args.push(lib_file.clone().into_os_string());
+                inputs.push(lib_file);
+            }
+            Mode::Dynamic => {
+                args.push(format!("-L{}", target.lib).into());
+                args.push(format!("-l{}", target.name).into());
+                inputs.push(self.lib_file(target));
+            }
+            _ => {}
+        }
+    }
+    //功能性函数，创建文件夹
+    fn mkdir(&self, p: &Path) -> SmResult<()> {
+        match fs::metadata(p) {
+            Ok(metadata) => {
+                if metadata.is_file() {
+                    return Err(SmError::io(
+                        p,
+                        io::Error::new(io::ErrorKind::AlreadyExists, "Naming conflict with a file"),
+                    ));
                 }
-                //obj状态，编译源代码
-                State::Obj => {
-                    let mut result = true;
-                    for (index, cmd) in self.obj_cmds.iter().enumerate() {
-                        let header = format!("[{}/{}]", index + 1, length);
-                        println!("{}: {}", header.bg(cyan_blue()), &cmd.meta_data);
-                        //执行
-                        let tmp = cmd.execute();
-                        //检查是否执行成功
-                        if !tmp {
-                            result = false;
-                            break;
+                Ok(())
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                // 目录不存在，创建它
+                match fs::create_dir_all(p) {
+                    Ok(_) => {
+                        if self.verbosity > Verbosity::Quiet {
+                            println!("Creating {} directory successfully.", p.display());
                         }
+                        Ok(())
                     }
-                    if result {
-                        //成功执行，没有报错，切换下一个状态
-                        self.state = State::Lib;
-                    } else {
-                        self.state = State::Failed;
-                    }
-                }
-                //lib状态，打包成库
-                State::Lib => {
-                    let header = format!("[{}/{}]", length - 1, length);
-                    println!("{}: {}", header.bg(cyan_blue()), self.lib_cmd.meta_data);
-                    //执行
-                    if self.lib_cmd.execute() {
-                        self.state = State::Bin;
-                    } else {
-                        self.state = State::Failed;
-                    }
-                }
-                //bin状态，编译成二进制文件
-                State::Bin => {
-                    let header = format!("[{}/{}]", length, length);
-                    println!("{}: {}", header.bg(cyan_blue()), &self.bin_cmd.meta_data);
-                    //执行
-                    if self.bin_cmd.execute() {
-                        self.state = State::End;
-                    } else {
-                        self.state = State::Failed;
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        if self.verbosity > Verbosity::Quiet {
+                            println!("Skipping {}", p.display());
+                        }
+                        Ok(())
                     }
+                    Err(e) => Err(SmError::io(p, e)),
                 }
             }
+            // 处理其他错误
+            Err(e) => Err(SmError::io(p, e)),
         }
     }
-    fn dyn_run(&mut self) {
-        let length = self.obj_cmds.len() + 2;
-        //FSM，有限状态机
-        loop {
-            match self.state {
-                //初始状态，需要编译源代码为动态库文件，切换状态为lib
-                State::Start => {
-                    self.state = State::Lib;
-                }
-                //失败状态，输出提示信息，并切换状态为结束状态
-                State::Failed => {
-                    println!("{}", "Command aborting!".bg(red()));
-                    self.state = State::End;
+    //根据构建图里的编译步骤生成compile_commands.json，返回文件路径
+    pub fn write_compdb(&self) -> SmResult<PathBuf> {
+        let entries: Vec<CompileCommand> = self
+            .graph
+            .steps()
+            .iter()
+            .filter(|s| s.kind == StepKind::Compile)
+            .map(|s| CompileCommand {
+                directory: self.root.clone(),
+                file: s.inputs[0].clone(),
+                //编辑器不认识ccache这样的包装程序，去掉它
+                arguments: s.cmd.argv()[self.wrapper.iter().len()..].to_vec(),
+                output: s.output.clone(),
+            })
+            .collect();
+        compdb::write_compdb(&self.root, &entries)
+    }
+    //设置同时执行的最大任务数
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+    pub fn set_report(&mut self, format: Format, path: PathBuf) {
+        self.report = Some((format, path));
+    }
+    //删除已经不存在的源文件留下的目标文件、依赖文件和构建记录，
+    //只处理本次构建的目标，以及配置文件里已经删掉的目标
+    fn prune(&mut self) {
+        let mut live: HashSet<PathBuf> = HashSet::new();
+        let mut keys: HashSet<&str> = HashSet::new();
+        for step in self.graph.steps() {
+            keys.insert(&step.key);
+            if step.kind == StepKind::Compile {
+                live.insert(step.output.clone());
+                live.extend(step.depfile.iter().cloned());
+                //sm coverage生成的覆盖率数据
+                live.insert(step.output.with_extension("gcno"));
+                live.insert(step.output.with_extension("gcda"));
+            }
+        }
+        for name in &self.built {
+            self.prune_dir(&self.obj_dir.join(name), &live);
+        }
+        self.remove_unknown(&self.obj_dir, &self.known);
+        //只有构建了测试时才知道哪些测试已经不存在了
+        if let Some(tests) = &self.tests {
+            for name in tests {
+                self.prune_dir(&self.test_obj_dir.join(name), &live);
+            }
+            self.remove_unknown(&self.test_obj_dir, tests);
+        }
+        let (built, known, tests) = (&self.built, &self.known, &self.tests);
+        self.db.units.retain(|k, _| {
+            let target = k.split('/').next().unwrap_or_default();
+            keys.contains(k.as_str())
+                || match target.strip_prefix(TEST_KEY_PREFIX) {
+                    Some(_) => tests.is_none(),
+                    None => known.iter().any(|t| t == target) && !built.iter().any(|b| b == target),
                 }
-                //lib状态，打包成库
-                State::Lib => {
-                    let header = format!("[{}/{}]", length - 1, length);
-                    println!("{}: {}", header.bg(cyan_blue()), self.lib_cmd.meta_data);
-                    //执行
-                    if self.lib_cmd.execute() {
-                        self.state = State::Bin;
-                    } else {
-                        self.state = State::Failed;
-                    }
+        });
+        //打包、链接和自定义步骤的记录，键为产物的相对路径或者步骤名
+        let test_bin_dir = relative_to(&self.test_obj_dir.with_file_name("bin"), &self.root);
+        let artifacts = &self.artifacts;
+        self.db.steps.retain(|k, _| {
+            keys.contains(k.as_str())
+                || artifacts.contains(k)
+                || (tests.is_none() && Path::new(k).starts_with(&test_bin_dir))
+        });
+    }
+    //删除dir下面不在names里的子目录，它们属于已经删掉的目标或者测试
+    fn remove_unknown(&self, dir: &Path, names: &[String]) {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for e in entries.flatten() {
+                let name = e.file_name().to_string_lossy().into_owned();
+                if !names.contains(&name) {
+                    self.remove_stale(&e.path());
                 }
-                //bin状态，编译成二进制文件
-                State::Bin => {
-                    let header = format!("[{}/{}]", length, length);
-                    println!("{}: {}", header.bg(cyan_blue()), &self.bin_cmd.meta_data);
-                    //执行
-                    if self.bin_cmd.execute() {
-                        self.state = State::End;
-                    } else {
-                        self.state = State::Failed;
-                    }
+            }
+        }
+    }
+    //递归删除目录里不在live中的文件，返回目录是否已经空了
+    fn prune_dir(&self, dir: &Path, live: &HashSet<PathBuf>) -> bool {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => return false,
+        };
+        let mut empty = true;
+        for e in entries.flatten() {
+            let path = e.path();
+            let gone = if path.is_dir() {
+                //还要放目标文件的空目录不能删
+                self.prune_dir(&path, live)
+                    && !live.iter().any(|l| l.starts_with(&path))
+                    && fs::remove_dir(&path).is_ok()
+            } else if live.contains(&path) {
+                false
+            } else {
+                self.remove_stale(&path);
+                true
+            };
+            empty &= gone;
+        }
+        empty
+    }
+    fn remove_stale(&self, p: &Path) {
+        let removed = if p.is_dir() {
+            fs::remove_dir_all(p)
+        } else {
+            fs::remove_file(p)
+        };
+        if removed.is_ok() && self.verbosity == Verbosity::Verbose {
+            println!("Removing stale {}", p.display());
+        }
+    }
+    pub fn run(&mut self) -> SmResult<()> {
+        self.prune();
+        let start = Instant::now();
+        if events::is_json() {
+            events::emit(
+                "build-started",
+                json!({
+                    "targets": self.built,
+                    "jobs": self.jobs,
+                    "steps": self.graph.steps().len(),
+                }),
+            );
+        }
+        let result = self
+            .graph
+            .run(&mut self.db, self.jobs, self.verbosity, self.keep_going);
+        //无论成功与否，都把已经完成的部分记录下来
+        self.db.save(&self.db_path);
+        if let Some(c) = &self.cache {
+            c.save_stats();
+        }
+        let outcome = result?;
+        let success = outcome.failed.is_empty();
+        let diagnostics = &outcome.diagnostics;
+        if events::is_json() {
+            let count = |s: &str| diagnostics.iter().filter(|d| d.severity == s).count();
+            events::emit(
+                "build-finished",
+                json!({
+                    "success": success,
+                    "duration_ms": start.elapsed().as_millis() as u64,
+                    "errors": count("error"),
+                    "warnings": count("warning"),
+                    "failed": outcome.failed,
+                    "skipped": outcome.skipped,
+                }),
+            );
+        }
+        self.report_diagnostics(diagnostics)?;
+        match success {
+            true => Ok(()),
+            false => Err(SmError::Build("Command aborting!".to_string())),
+        }
+    }
+    //按文件打印错误和警告的个数，需要时写入报告文件
+    fn report_diagnostics(&self, diagnostics: &[diagnostic::Diagnostic]) -> SmResult<()> {
+        //只有note和remark的文件不列出来，一行都没有时连标题也不打印
+        let rows: Vec<String> = diagnostic::count_by_file(diagnostics)
+            .into_iter()
+            .filter(|(_, (errors, warnings))| errors + warnings > 0)
+            .map(|(file, (errors, warnings))| {
+                let mut counts: Vec<String> = Vec::new();
+                if errors > 0 {
+                    counts.push(plural(errors, "error"));
                 }
-                _ => {
-                    break;
+                if warnings > 0 {
+                    counts.push(plural(warnings, "warning"));
                 }
+                format!("  {}: {}", file, counts.join(", "))
+            })
+            .collect();
+        if !rows.is_empty() && self.verbosity > Verbosity::Quiet {
+            println!("Diagnostics:");
+            for row in rows {
+                println!("{}", row);
+            }
+        }
+        if let Some((format, path)) = &self.report {
+            diagnostic::write_report(path, *format, diagnostics, &self.root)?;
+            if self.verbosity > Verbosity::Quiet {
+                println!("Writing {} successfully.", path.display());
             }
         }
+        Ok(())
     }
 }
 
-//功能性函数，创建文件夹
-fn mkdir(p: &PathBuf) {
-    match fs::metadata(p) {
-        Ok(metadata) => {
-            if metadata.is_file() {
-                panic!("Naming conflict!");
-            }
+//打印命令的输出，没有输出时不打印空行
+pub fn print_output(text: &str) {
+    if !text.is_empty() {
+        print!("{}", text);
+        if !text.ends_with('\n') {
+            println!();
         }
-        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-            // 目录不存在，创建它
-            match fs::create_dir(p) {
-                Ok(_) => {
-                    println!("Creating {} directory successfully.", p.to_str().unwrap())
-                }
-                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                    println!("Skipping {}", p.to_str().unwrap());
-                }
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
-            }
+    }
+}
+
+//源文件在目标文件目录里对应的相对路径，..换成__，项目外的绝对路径放在__abs下面
+fn mirror_path(src: &Path, root: &Path) -> PathBuf {
+    let (mut mirror, rel) = match src.strip_prefix(root) {
+        Ok(rel) => (PathBuf::new(), rel),
+        Err(_) => (PathBuf::from("__abs"), src),
+    };
+    for c in rel.components() {
+        match c {
+            Component::Normal(n) => mirror.push(n),
+            Component::ParentDir => mirror.push("__"),
+            _ => {}
         }
-        Err(e) => {
-            // 处理其他错误
-            eprintln!("{}", e);
+    }
+    mirror
+}
+
+//在文件名后面追加扩展名，a.cpp变成a.cpp.o
+fn append_ext(p: &Path, ext: &str) -> PathBuf {
+    let mut s = p.as_os_str().to_os_string();
+    s.push(".");
+    s.push(ext);
+    PathBuf::from(s)
+}
+
+//追加参数，已经存在的不再重复添加
+fn push_unique(flags: &mut Vec<String>, new: Vec<String>) {
+    for f in new {
+        if !flags.contains(&f) {
+            flags.push(f);
         }
     }
 }
+
+//默认任务数为CPU核数
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+//求出文件相对于项目根目录的路径，只用作构建数据库的键和显示
+pub fn relative_to(p: &Path, root: &Path) -> String {
+    p.strip_prefix(root)
+        .unwrap_or(p)
+        .to_string_lossy()
+        .into_owned()
+}
+
+//带上单复数的个数，比如1 error、2 warnings
+fn plural(n: usize, word: &str) -> String {
+    match n {
+        1 => format!("1 {}", word),
+        _ => format!("{} {}s", n, word),
+    }
+}
+
+//给参数加上单引号，只包含安全字符的参数保持原样
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_safe_arguments_alone() {
+        assert_eq!(shell_quote("-std=c++17"), "-std=c++17");
+        assert_eq!(
+            shell_quote("/tmp/demo/src/main.cpp"),
+            "/tmp/demo/src/main.cpp"
+        );
+        assert_eq!(shell_quote("-Wl,-rpath,lib"), "-Wl,-rpath,lib");
+    }
+
+    #[test]
+    fn quotes_spaces_quotes_and_empty_arguments() {
+        assert_eq!(shell_quote("-Imy inc"), "'-Imy inc'");
+        assert_eq!(shell_quote("-DNAME=\"sm\""), "'-DNAME=\"sm\"'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn displays_the_whole_command() {
+        let mut cmd = OneLineCommand::new("g++");
+        cmd.args(["-c", "a b.cpp", "-o", "a b.o"]);
+        assert_eq!(cmd.display(), "g++ -c 'a b.cpp' -o 'a b.o'");
+    }
+}
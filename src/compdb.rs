@@ -0,0 +1,31 @@
+/*
+生成compile_commands.json，给clangd之类的工具使用，
+内容直接来自构建图里的编译步骤，保证和实际执行的命令一致
+*/
+
+use crate::error::{SmError, SmResult};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//compile_commands.json里的一条记录
+#[derive(Serialize)]
+pub struct CompileCommand {
+    pub directory: PathBuf,
+    pub file: PathBuf,
+    pub arguments: Vec<String>,
+    pub output: PathBuf,
+}
+
+//写入项目根目录下的compile_commands.json，内容没有变化时不改动文件，
+//避免编辑器反复重新索引
+pub fn write_compdb(root: &Path, entries: &[CompileCommand]) -> SmResult<PathBuf> {
+    let path = root.join("compile_commands.json");
+    let mut content = serde_json::to_string_pretty(entries)
+        .map_err(|e| SmError::Build(format!("Can't serialize compile commands: {}", e)))?;
+    content.push('\n');
+    if fs::read_to_string(&path).ok().as_deref() != Some(content.as_str()) {
+        fs::write(&path, content).map_err(|e| SmError::io(&path, e))?;
+    }
+    Ok(path)
+}
@@ -1,113 +1,1164 @@
-use std::env;
 /*
 主要负责把project.toml里面的内容给读出来
 */
-use serde::Deserialize;
-use std::fs::{read_dir, read_to_string};
-use std::path::PathBuf;
+use crate::cache;
+use crate::error::{SmError, SmResult};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{canonicalize, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
 
 #[derive(Deserialize)]
 pub struct Project {
-    pub target: Target,
+    //旧格式只有一个[target]，新格式可以写多个[[target]]
+    pub target: Targets,
     pub complier: Complier,
+    //[profile.xxx]，覆盖complier里的设置
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+    //[toolchain.xxx]，交叉编译用的工具链
+    #[serde(default)]
+    pub toolchain: BTreeMap<String, CrossToolchain>,
+    //[[test]]，不写时把tests目录下的每个源文件当作一个测试
+    #[serde(default)]
+    pub test: Vec<Test>,
+    //[[step]]，自定义的构建步骤，比如生成代码
+    #[serde(default)]
+    pub step: Vec<CustomStep>,
+    //当前使用的profile，为空时不覆盖任何设置，产物放在原来的位置
+    #[serde(skip)]
+    pub selected: String,
+    //当前使用的工具链及其名字，为None时使用本机的编译器
+    #[serde(skip)]
+    pub cross: Option<CrossToolchain>,
+    #[serde(skip)]
+    pub cross_name: String,
+    //项目根目录，也就是project.toml所在的目录
+    #[serde(skip)]
+    pub root: PathBuf,
+}
+
+pub enum Targets {
+    One(Box<Target>),
+    Many(Vec<Target>),
+}
+
+//按照写的是表还是数组选择格式，不用untagged，里面的错误才能带上位置和字段名
+impl<'de> Deserialize<'de> for Targets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Targets, D::Error> {
+        struct TargetsVisitor;
+        impl<'de> Visitor<'de> for TargetsVisitor {
+            type Value = Targets;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a [target] table or [[target]] array")
+            }
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Targets, A::Error> {
+                let target = Target::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(Targets::One(Box::new(target)))
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Targets, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(Targets::Many)
+            }
+        }
+        deserializer.deserialize_any(TargetsVisitor)
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Target {
     pub name: String,
+    #[serde(default = "default_inc")]
     pub inc: String,
+    //源文件目录，可以为空
+    #[serde(default)]
     pub src: String,
+    //源文件的glob，比如["src/**/*.cpp"]，写了它就不再遍历src
+    #[serde(default)]
+    pub sources: Vec<String>,
+    //需要排除的文件的glob，比如["src/legacy/**"]
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    //会被编译的源文件扩展名
+    #[serde(default = "default_extensions")]
+    pub extensions: Vec<String>,
+    //包含main函数的源文件，库目标写了它就会额外生成同名的可执行文件
+    #[serde(default)]
     pub entrance: String,
+    //旧格式的编译模式：sta、dyn
+    #[serde(default)]
     pub mode: String,
+    //新格式的目标类型：static、shared、exe、header
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default = "default_lib")]
     pub lib: String,
+    #[serde(default = "default_bin")]
     pub bin: String,
+    //依赖的同一个文件里的其他目标
+    #[serde(default)]
+    pub deps: Vec<String>,
+    //只有自己使用的编译、链接设置，直接写在目标里，和Usage的字段一样，
+    //不用flatten，出错时才能知道是哪一行
+    #[serde(default)]
+    pub defines: Defines,
+    #[serde(default)]
+    pub include_dirs: Vec<String>,
+    #[serde(default)]
+    pub system_include_dirs: Vec<String>,
+    #[serde(default)]
+    pub lib_dirs: Vec<String>,
+    #[serde(default)]
+    pub link_args: Vec<String>,
+    //依赖它的目标也会使用的设置，写在[target.public]里
+    #[serde(default)]
+    pub public: Usage,
+}
+
+//一个测试程序，和项目的库链接在一起，由sm test构建并运行
+#[derive(Deserialize, Clone)]
+pub struct Test {
+    pub name: String,
+    //测试的源文件或者glob，比如["tests/test_add.cpp"]
+    pub sources: Vec<String>,
+    //链接的目标，不写时链接所有的库目标
+    #[serde(default)]
+    pub deps: Vec<String>,
+    //运行时传给测试程序的参数
+    #[serde(default)]
+    pub args: Vec<String>,
+    //只有这个测试使用的编译、链接设置
+    #[serde(default)]
+    pub defines: Defines,
+    #[serde(default)]
+    pub include_dirs: Vec<String>,
+    #[serde(default)]
+    pub system_include_dirs: Vec<String>,
+    #[serde(default)]
+    pub lib_dirs: Vec<String>,
+    #[serde(default)]
+    pub link_args: Vec<String>,
+}
+
+//自定义步骤，执行一条命令，由inputs生成outputs，路径都相对于项目根目录，
+//生成的头文件会在编译之前准备好
+#[derive(Deserialize)]
+pub struct CustomStep {
+    pub name: String,
+    //程序和参数，比如["protoc", "--cpp_out=gen", "proto/a.proto"]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+//目标的预处理宏、头文件目录和库目录等设置
+#[derive(Deserialize, Default, Clone)]
+pub struct Usage {
+    #[serde(default)]
+    pub defines: Defines,
+    #[serde(default)]
+    pub include_dirs: Vec<String>,
+    //用-isystem添加，里面的头文件不会产生警告
+    #[serde(default)]
+    pub system_include_dirs: Vec<String>,
+    #[serde(default)]
+    pub lib_dirs: Vec<String>,
+    //原样传给链接器的参数
+    #[serde(default)]
+    pub link_args: Vec<String>,
+}
+
+//预处理宏，可以写成["NDEBUG", "LEVEL=2"]或者{ NDEBUG = "", LEVEL = 2 }
+#[derive(Clone)]
+pub enum Defines {
+    List(Vec<String>),
+    Map(BTreeMap<String, toml::Value>),
+}
+
+impl<'de> Deserialize<'de> for Defines {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Defines, D::Error> {
+        struct DefinesVisitor;
+        impl<'de> Visitor<'de> for DefinesVisitor {
+            type Value = Defines;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array of macros or a table of macro values")
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Defines, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(Defines::List)
+            }
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Defines, A::Error> {
+                BTreeMap::deserialize(MapAccessDeserializer::new(map)).map(Defines::Map)
+            }
+        }
+        deserializer.deserialize_any(DefinesVisitor)
+    }
+}
+
+impl Default for Defines {
+    fn default() -> Defines {
+        Defines::List(Vec::new())
+    }
+}
+
+impl Defines {
+    //转换成-D参数，值为空字符串时只定义宏名
+    pub fn flags(&self) -> Vec<String> {
+        match self {
+            Defines::List(l) => l.iter().map(|d| format!("-D{}", d)).collect(),
+            Defines::Map(m) => m
+                .iter()
+                .map(|(k, v)| match v {
+                    toml::Value::String(s) if s.is_empty() => format!("-D{}", k),
+                    toml::Value::String(s) => format!("-D{}={}", k, s),
+                    toml::Value::Boolean(b) => format!("-D{}={}", k, *b as i32),
+                    _ => format!("-D{}={}", k, v),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Usage {
+    //编译目标文件时需要的参数
+    pub fn compile_flags(&self) -> Vec<String> {
+        let mut flags: Vec<String> = Vec::new();
+        flags.extend(self.include_dirs.iter().map(|d| format!("-I{}", d)));
+        flags.extend(
+            self.system_include_dirs
+                .iter()
+                .map(|d| format!("-isystem{}", d)),
+        );
+        flags.extend(self.defines.flags());
+        flags
+    }
+    //链接时需要的参数
+    pub fn link_flags(&self) -> Vec<String> {
+        let mut flags: Vec<String> = self.lib_dirs.iter().map(|d| format!("-L{}", d)).collect();
+        flags.extend(self.link_args.iter().cloned());
+        flags
+    }
+}
+
+fn default_inc() -> String {
+    "inc".to_string()
+}
+
+fn default_extensions() -> Vec<String> {
+    ["cpp", "cxx", "cc", "c++", "c"]
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
+}
+
+fn default_lib() -> String {
+    "lib".to_string()
+}
+
+fn default_bin() -> String {
+    "bin".to_string()
 }
 
 #[derive(Deserialize)]
 pub struct Complier {
+    //C++编译器的名字或者路径，比如g++-13、/opt/llvm/bin/clang++、"ccache g++"，
+    //为空时自动查找，环境变量CXX优先
+    #[serde(default)]
     pub cxx: String,
-    pub std: i64,
+    pub std: Standard,
     pub wall: bool,
     pub ol: i8,
     pub link: Vec<String>,
     pub extra: Vec<String>,
+    //编译.c文件使用的C编译器，为空时使用和cxx配套的那个，环境变量CC优先
+    #[serde(default)]
+    pub cc: String,
+    //C标准，不写时使用编译器的默认值
+    #[serde(default)]
+    pub c_std: Option<i64>,
+    //只在编译.c文件时添加的参数
+    #[serde(default)]
+    pub c_extra: Vec<String>,
+    //打包静态库的工具，默认为ar，环境变量AR优先
+    #[serde(default)]
+    pub ar: String,
+    //编译缓存，可以是ccache、sccache这样的包装程序，也可以是sm使用内置的缓存
+    #[serde(default)]
+    pub cache: String,
+    //内置缓存的目录，可以在多个项目之间共用，默认为~/.cache/sm
+    #[serde(default)]
+    pub cache_dir: String,
+}
+
+//C++标准，可以写成17，也可以写成"c++23"、"gnu++20"
+#[derive(Clone)]
+pub enum Standard {
+    Number(i64),
+    Name(String),
+}
+
+impl<'de> Deserialize<'de> for Standard {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Standard, D::Error> {
+        struct StandardVisitor;
+        impl Visitor<'_> for StandardVisitor {
+            type Value = Standard;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a C++ standard like 17 or \"gnu++20\"")
+            }
+            fn visit_i64<E: de::Error>(self, n: i64) -> Result<Standard, E> {
+                Ok(Standard::Number(n))
+            }
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Standard, E> {
+                Ok(Standard::Name(s.to_string()))
+            }
+        }
+        deserializer.deserialize_any(StandardVisitor)
+    }
+}
+
+impl Standard {
+    //拆分成方言和年份，比如gnu++20拆成gnu++和20
+    fn parts(&self) -> Option<(&str, i64)> {
+        match self {
+            Standard::Number(n) => Some(("c++", *n)),
+            Standard::Name(s) => {
+                let (dialect, year) = match s.strip_prefix("gnu++") {
+                    Some(y) => ("gnu++", y),
+                    None => ("c++", s.strip_prefix("c++").unwrap_or(s)),
+                };
+                Some((dialect, year.parse().ok()?))
+            }
+        }
+    }
+    //-std=后面的名字，不支持的标准返回None
+    pub fn name(&self) -> Option<String> {
+        match self.parts()? {
+            (d, y @ (98 | 11 | 14 | 17 | 20 | 23 | 26)) => Some(format!("{}{}", d, y)),
+            _ => None,
+        }
+    }
+    //老版本编译器里使用的草案名字
+    pub fn draft_name(&self) -> Option<String> {
+        let draft = match self.parts()? {
+            (_, 20) => "2a",
+            (_, 23) => "2b",
+            (_, 26) => "2c",
+            _ => return None,
+        };
+        Some(format!("{}{}", self.parts()?.0, draft))
+    }
+}
+
+impl fmt::Display for Standard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Standard::Number(n) => write!(f, "{}", n),
+            Standard::Name(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+//交叉编译工具链，可以写在[toolchain.xxx]里，也可以单独写成一个文件
+#[derive(Deserialize, Default, Clone)]
+pub struct CrossToolchain {
+    //单独的文件里可以指定名字，用来区分输出目录，默认为文件名
+    #[serde(default)]
+    pub name: String,
+    //目标平台，比如aarch64-linux-gnu，没有写cxx、ar时用它拼出工具的名字
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub cxx: String,
+    #[serde(default)]
+    pub cc: String,
+    #[serde(default)]
+    pub ar: String,
+    #[serde(default)]
+    pub sysroot: String,
+    //编译和链接时都要添加的参数
+    #[serde(default)]
+    pub flags: Vec<String>,
+    //只在链接时添加的参数
+    #[serde(default)]
+    pub link_flags: Vec<String>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+pub struct Profile {
+    pub ol: Option<i8>,
+    pub wall: Option<bool>,
+    //是否生成调试信息(-g)
+    pub debug: Option<bool>,
+    //预处理宏，比如["NDEBUG", "LEVEL=2"]
+    #[serde(default)]
+    pub defines: Vec<String>,
+    //启用的sanitizer，比如["address", "undefined"]
+    #[serde(default)]
+    pub sanitizers: Vec<String>,
+    #[serde(default)]
+    pub extra: Vec<String>,
+}
+
+impl Profile {
+    //内置的debug和release，配置文件里同名的profile在此基础上覆盖
+    fn builtin(name: &str) -> Option<Profile> {
+        match name {
+            "debug" => Some(Profile {
+                ol: Some(0),
+                debug: Some(true),
+                ..Profile::default()
+            }),
+            "release" => Some(Profile {
+                ol: Some(3),
+                debug: Some(false),
+                defines: vec!["NDEBUG".to_string()],
+                ..Profile::default()
+            }),
+            _ => None,
+        }
+    }
+    //用other里写了的设置覆盖自己，列表则是追加
+    fn merge(mut self, other: &Profile) -> Profile {
+        self.ol = other.ol.or(self.ol);
+        self.wall = other.wall.or(self.wall);
+        self.debug = other.debug.or(self.debug);
+        self.defines.extend(other.defines.iter().cloned());
+        self.sanitizers.extend(other.sanitizers.iter().cloned());
+        self.extra.extend(other.extra.iter().cloned());
+        self
+    }
+    //转换成编译器参数，sanitizer在链接时也需要
+    fn flags(&self) -> Vec<String> {
+        let mut flags: Vec<String> = Vec::new();
+        if self.debug == Some(true) {
+            flags.push("-g".to_string());
+        }
+        for d in &self.defines {
+            flags.push(format!("-D{}", d));
+        }
+        if !self.sanitizers.is_empty() {
+            flags.push(format!("-fsanitize={}", self.sanitizers.join(",")));
+        }
+        flags.extend(self.extra.iter().cloned());
+        flags
+    }
 }
 
 //编译模式
+#[derive(Clone, Copy, PartialEq)]
 pub enum Mode {
     Static,
     Dynamic,
+    Executable,
+    HeaderOnly,
     Invalid,
 }
 
-impl Project {
-    pub fn new(config_path: &PathBuf) -> Project {
-        //读取文件内容
-        let content = read_to_string(config_path).unwrap();
-        return toml::from_str(&content).unwrap();
-    }
-    //获取编译模式
+impl Target {
+    //获取编译模式，新格式的kind优先
     pub fn get_mode(&self) -> Mode {
-        match self.target.mode.as_str() {
-            "sta" => Mode::Static,
-            "dyn" => Mode::Dynamic,
+        let kind = if self.kind.is_empty() {
+            &self.mode
+        } else {
+            &self.kind
+        };
+        match kind.as_str() {
+            "sta" | "static" => Mode::Static,
+            "dyn" | "shared" => Mode::Dynamic,
+            "exe" | "executable" => Mode::Executable,
+            "header" => Mode::HeaderOnly,
             _ => Mode::Invalid,
         }
     }
-    //检查c++标准是否正确
-    pub fn check_std(&self) -> bool {
-        match self.complier.std {
-            98 | 11 | 14 | 17 | 20 => true,
+    //是否会生成可执行文件
+    pub fn has_binary(&self) -> bool {
+        match self.get_mode() {
+            Mode::Executable => true,
+            Mode::Static | Mode::Dynamic => !self.entrance.is_empty(),
             _ => false,
         }
     }
-    //检查代码优化等级是否正确
-    pub fn check_ol(&self) -> bool {
-        match self.complier.ol {
-            0..=3 => true,
-            _ => false,
+    //获取需要编译的源文件数组，按路径排好序
+    pub fn get_src_files(&self, root: &Path) -> SmResult<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        //写了sources时只遍历每个glob里固定的那部分目录
+        let include = if !self.sources.is_empty() {
+            for pattern in &self.sources {
+                let base = root.join(glob_base(pattern));
+                if base.exists() {
+                    visit_dirs(&base, &mut files)?;
+                }
+            }
+            Some(build_globset("target.sources", &self.sources)?)
+        } else if !self.src.is_empty() {
+            visit_dirs(&root.join(&self.src), &mut files)?;
+            None
+        } else {
+            return Ok(files);
+        };
+        let exclude = build_globset("target.exclude", &self.exclude)?;
+        files.retain(|f| {
+            let rel = f.strip_prefix(root).unwrap_or(f);
+            self.is_source(f)
+                && include.as_ref().is_none_or(|set| set.is_match(rel))
+                && !exclude.is_match(rel)
+        });
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+    //目标的源码所在的目录，统计覆盖率时只看这些目录下的文件
+    pub fn source_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = vec![PathBuf::from(&self.inc)];
+        if !self.src.is_empty() {
+            dirs.push(PathBuf::from(&self.src));
         }
+        dirs.extend(self.sources.iter().map(|p| glob_base(p)));
+        dirs.retain(|d| !d.as_os_str().is_empty());
+        dirs
     }
-    //检查编译器是否设置正确
-    pub fn check_complier(&self) -> bool {
-        match self.complier.cxx.as_str() {
-            "g++" | "clang++" => true,
-            _ => false,
+    //只有自己使用的设置
+    pub fn private(&self) -> Usage {
+        Usage {
+            defines: self.defines.clone(),
+            include_dirs: self.include_dirs.clone(),
+            system_include_dirs: self.system_include_dirs.clone(),
+            lib_dirs: self.lib_dirs.clone(),
+            link_args: self.link_args.clone(),
+        }
+    }
+    //根据扩展名判断是否需要编译，头文件永远不编译
+    fn is_source(&self, file: &Path) -> bool {
+        let ext = match file.extension() {
+            Some(e) => e.to_string_lossy(),
+            None => return false,
+        };
+        if is_header(file) {
+            return false;
+        }
+        self.extensions
+            .iter()
+            .any(|e| e.trim_start_matches('.') == ext)
+    }
+}
+
+//头文件的扩展名
+const HEADER_EXTENSIONS: [&str; 7] = ["h", "hh", "hpp", "hxx", "h++", "inl", "ipp"];
+
+//根据扩展名判断是否是头文件
+pub fn is_header(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|e| HEADER_EXTENSIONS.contains(&e.to_string_lossy().as_ref()))
+}
+
+//递归遍历函数，收集所有文件，跳过.sm、.git这样的隐藏目录
+fn visit_dirs(dir: &Path, files: &mut Vec<PathBuf>) -> SmResult<()> {
+    //递归退出条件
+    if dir.is_file() {
+        files.push(dir.to_path_buf());
+        return Ok(());
+    }
+    //递归遍历
+    let entries = read_dir(dir).map_err(|e| SmError::io(dir, e))?;
+    for p in entries.flatten() {
+        if p.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        visit_dirs(&p.path(), files)?;
+    }
+    Ok(())
+}
+
+//glob里第一个通配符之前的目录，比如src/**/*.cpp对应src
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| {
+            !c.as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '[', '{'])
+        })
+        .collect()
+}
+
+//把glob列表编译成一个集合，*不会匹配路径分隔符，**可以匹配多层目录
+fn build_globset(field: &str, patterns: &[String]) -> SmResult<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for p in patterns {
+        let glob = GlobBuilder::new(p)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| SmError::config(field, format!("Invalid pattern {}: {}", p, e)))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| SmError::config(field, e.to_string()))
+}
+
+impl Project {
+    pub fn new(config_path: &PathBuf) -> SmResult<Project> {
+        //读取文件内容
+        let content = read_to_string(config_path).map_err(|e| SmError::io(config_path, e))?;
+        let mut project: Project =
+            toml::from_str(&content).map_err(|e| SmError::parse(config_path, &content, e))?;
+        //项目根目录就是project.toml所在的目录
+        let parent = match config_path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        //转换成绝对路径，切换工作目录之后也不会出错
+        project.root = canonicalize(parent).map_err(|e| SmError::io(parent, e))?;
+        project.validate()?;
+        Ok(project)
+    }
+    //检查配置项的值，出错时指出具体的字段
+    fn validate(&self) -> SmResult<()> {
+        if !self.check_std() {
+            return Err(SmError::config(
+                "complier.std",
+                format!(
+                    "C++ standard {} is invaild, expected 98, 11, 14, 17, 20, 23 or 26, optionally written as \"gnu++20\"",
+                    self.complier.std
+                ),
+            ));
+        }
+        if !self.check_c_std() {
+            return Err(SmError::config(
+                "complier.c_std",
+                format!(
+                    "C standard {} is invaild, expected 89, 99, 11, 17 or 23",
+                    self.complier.c_std.unwrap_or_default()
+                ),
+            ));
+        }
+        if !self.check_ol() {
+            return Err(SmError::config(
+                "complier.ol",
+                format!(
+                    "Optimization level {} is invaild, expected 0 to 3",
+                    self.complier.ol
+                ),
+            ));
+        }
+        for (i, t) in self.targets().iter().enumerate() {
+            //目标按名字查找，重名的目标只有第一个会被构建
+            if self.targets()[..i].iter().any(|o| o.name == t.name) {
+                return Err(SmError::config(
+                    "target.name",
+                    format!("Target {} is defined more than once", t.name),
+                ));
+            }
+            if t.get_mode() == Mode::Invalid {
+                let field = if t.kind.is_empty() { "mode" } else { "kind" };
+                return Err(SmError::config(
+                    &format!("target.{}", field),
+                    format!("Target {} has an unsupported {}", t.name, field),
+                ));
+            }
+        }
+        for (i, s) in self.step.iter().enumerate() {
+            if s.name.is_empty() || self.step[..i].iter().any(|o| o.name == s.name) {
+                return Err(SmError::config(
+                    "step.name",
+                    format!("Step name \"{}\" is empty or used twice", s.name),
+                ));
+            }
+            if s.command.is_empty() {
+                return Err(SmError::config(
+                    "step.command",
+                    format!("Step {} has no command", s.name),
+                ));
+            }
+            if s.outputs.is_empty() {
+                return Err(SmError::config(
+                    "step.outputs",
+                    format!("Step {} has no outputs", s.name),
+                ));
+            }
         }
+        Ok(())
     }
-    //递归遍历函数
-    fn visit_dirs(&self, dir: &PathBuf, src_files: &mut Vec<PathBuf>) {
-        //递归退出条件
-        if dir.is_file() {
-            //收集c++源文件
-            match dir.extension() {
-                Some(s) => {
-                    if s == "cxx" || s == "cpp" || s == "hpp" {
-                        //添加到数组
-                        src_files.push(dir.to_path_buf());
-                    }
+    //切换到指定的profile，覆盖编译设置，并且把产物放到各自的子目录里
+    pub fn apply_profile(&mut self, name: &str) -> SmResult<()> {
+        let profile = match (Profile::builtin(name), self.profile.get(name)) {
+            (Some(b), Some(p)) => b.merge(p),
+            (Some(b), None) => b,
+            (None, Some(p)) => p.clone(),
+            (None, None) => {
+                return Err(SmError::config(
+                    "profile",
+                    format!("Profile {} does not exist", name),
+                ))
+            }
+        };
+        if let Some(ol) = profile.ol {
+            self.complier.ol = ol;
+        }
+        if let Some(wall) = profile.wall {
+            self.complier.wall = wall;
+        }
+        self.complier.extra.extend(profile.flags());
+        self.push_output_dir(name);
+        self.selected = name.to_string();
+        //profile里也可能写了不正确的优化等级
+        self.validate()
+    }
+    //切换到交叉编译工具链，spec为[toolchain.xxx]的名字或者工具链文件的路径，
+    //产物放到以工具链名字命名的子目录里，和本机的产物互不影响
+    pub fn apply_toolchain(&mut self, spec: &str) -> SmResult<()> {
+        let path = Path::new(spec);
+        let (name, cross) = if spec.ends_with(".toml") || path.is_file() {
+            let content = read_to_string(path).map_err(|e| SmError::io(path, e))?;
+            let cross: CrossToolchain =
+                toml::from_str(&content).map_err(|e| SmError::parse(path, &content, e))?;
+            let name = if cross.name.is_empty() {
+                path.file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            } else {
+                cross.name.clone()
+            };
+            (name, cross)
+        } else {
+            match self.toolchain.get(spec) {
+                Some(t) => (spec.to_string(), t.clone()),
+                None => {
+                    return Err(SmError::config(
+                        "toolchain",
+                        format!("Toolchain {} does not exist", spec),
+                    ))
                 }
-                None => {}
             }
-            return;
+        };
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(SmError::config(
+                "toolchain.name",
+                format!("Toolchain name {} can't be used as a directory name", name),
+            ));
+        }
+        self.push_output_dir(&name);
+        self.cross = Some(cross);
+        self.cross_name = name;
+        Ok(())
+    }
+    //sm coverage使用的设置，关闭优化并加上插桩参数，产物放到coverage子目录里，
+    //sm的缓存里的目标文件没有对应的.gcno，所以不使用它
+    pub fn apply_coverage(&mut self, flags: Vec<String>) {
+        self.complier.ol = 0;
+        self.complier.extra.extend(flags);
+        if self.complier.cache == "sm" {
+            self.complier.cache.clear();
+        }
+        self.push_output_dir("coverage");
+        self.selected = match self.selected.as_str() {
+            "" => "coverage".to_string(),
+            s => format!("{}/coverage", s),
+        };
+    }
+    //把所有目标的库和可执行文件放到下一级子目录里
+    fn push_output_dir(&mut self, name: &str) {
+        let targets: &mut [Target] = match &mut self.target {
+            Targets::One(t) => std::slice::from_mut(&mut **t),
+            Targets::Many(ts) => ts,
+        };
+        for t in targets {
+            t.lib = format!("{}/{}", t.lib, name);
+            t.bin = format!("{}/{}", t.bin, name);
+        }
+    }
+    //内置缓存的目录，相对路径以项目根目录为准，环境变量SM_CACHE_DIR优先
+    pub fn cache_dir(&self) -> PathBuf {
+        if self.complier.cache_dir.is_empty() || env::var_os("SM_CACHE_DIR").is_some() {
+            cache::default_dir()
+        } else {
+            self.root.join(&self.complier.cache_dir)
+        }
+    }
+    //存放中间文件的目录
+    pub fn sm_dir(&self) -> PathBuf {
+        let mut dir = self.root.join(".sm");
+        if !self.cross_name.is_empty() {
+            dir.push(&self.cross_name);
+        }
+        if !self.selected.is_empty() {
+            dir.push(&self.selected);
+        }
+        dir
+    }
+    //所有目标
+    pub fn targets(&self) -> &[Target] {
+        match &self.target {
+            Targets::One(t) => std::slice::from_ref(&**t),
+            Targets::Many(ts) => ts,
+        }
+    }
+    //根据名字查找目标
+    pub fn find_target(&self, name: &str) -> Option<&Target> {
+        self.targets().iter().find(|t| t.name == name)
+    }
+    //默认运行的目标，也就是第一个会生成可执行文件的目标
+    pub fn default_binary(&self) -> Option<&Target> {
+        self.targets().iter().find(|t| t.has_binary())
+    }
+    //所有测试，没有写[[test]]时tests目录下的每个源文件都是一个测试，名字为文件名
+    pub fn tests(&self) -> SmResult<Vec<Test>> {
+        let mut tests = self.test.clone();
+        let dir = self.root.join("tests");
+        if tests.is_empty() && dir.is_dir() {
+            let mut files: Vec<PathBuf> = read_dir(&dir)
+                .map_err(|e| SmError::io(&dir, e))?
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.is_file())
+                .collect();
+            files.sort();
+            for f in files {
+                let ext = f.extension().map(|e| e.to_string_lossy().into_owned());
+                if !ext.is_some_and(|e| default_extensions().contains(&e)) {
+                    continue;
+                }
+                tests.push(Test {
+                    name: f
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    sources: vec![format!(
+                        "tests/{}",
+                        f.file_name().unwrap_or_default().to_string_lossy()
+                    )],
+                    deps: Vec::new(),
+                    args: Vec::new(),
+                    defines: Defines::default(),
+                    include_dirs: Vec::new(),
+                    system_include_dirs: Vec::new(),
+                    lib_dirs: Vec::new(),
+                    link_args: Vec::new(),
+                });
+            }
+        }
+        //默认链接所有的库目标，只有头文件的目标提供头文件目录
+        let libs: Vec<String> = self
+            .targets()
+            .iter()
+            .filter(|t| t.get_mode() != Mode::Executable)
+            .map(|t| t.name.clone())
+            .collect();
+        //测试的产物放在单独的目录里，可以和目标同名
+        for (i, t) in tests.iter().enumerate() {
+            if tests[..i].iter().any(|o| o.name == t.name) {
+                return Err(SmError::config(
+                    "test.name",
+                    format!("Test {} has the same name as another test", t.name),
+                ));
+            }
+        }
+        for t in &mut tests {
+            if t.deps.is_empty() {
+                t.deps = libs.clone();
+            }
+            if let Some(d) = t.deps.iter().find(|d| self.find_target(d).is_none()) {
+                return Err(SmError::config(
+                    "test.deps",
+                    format!("Test {} depends on unknown target {}", t.name, d),
+                ));
+            }
+        }
+        Ok(tests)
+    }
+    //把测试当作一个可执行文件目标，放在.sm/tests/bin里
+    pub fn test_target(&self, test: &Test) -> Target {
+        Target {
+            name: test.name.clone(),
+            inc: "tests".to_string(),
+            src: String::new(),
+            sources: test.sources.clone(),
+            exclude: Vec::new(),
+            extensions: default_extensions(),
+            entrance: String::new(),
+            mode: String::new(),
+            kind: "exe".to_string(),
+            lib: default_lib(),
+            bin: self
+                .sm_dir()
+                .join("tests")
+                .join("bin")
+                .to_string_lossy()
+                .into_owned(),
+            deps: test.deps.clone(),
+            defines: test.defines.clone(),
+            include_dirs: test.include_dirs.clone(),
+            system_include_dirs: test.system_include_dirs.clone(),
+            lib_dirs: test.lib_dirs.clone(),
+            link_args: test.link_args.clone(),
+            public: Usage::default(),
         }
-        //递归遍历
-        for d in read_dir(dir).expect("Can't read dir!") {
-            match d {
-                Ok(p) => {
-                    self.visit_dirs(&p.path(), src_files);
+    }
+    //求出需要构建的目标，依赖排在前面，不指定名字时构建所有目标
+    pub fn resolve(&self, name: Option<&str>) -> SmResult<Vec<&Target>> {
+        let roots: Vec<&Target> = match name {
+            Some(n) => match self.find_target(n) {
+                Some(t) => vec![t],
+                None => {
+                    return Err(SmError::Usage(format!("Target {} does not exist", n)));
+                }
+            },
+            None => self.targets().iter().collect(),
+        };
+        let mut result: Vec<&Target> = Vec::new();
+        let mut visiting: Vec<&str> = Vec::new();
+        for t in roots {
+            self.visit_deps(t, &mut visiting, &mut result)?;
+        }
+        Ok(result)
+    }
+    //深度优先遍历依赖，顺便检查循环依赖
+    fn visit_deps<'a>(
+        &'a self,
+        target: &'a Target,
+        visiting: &mut Vec<&'a str>,
+        result: &mut Vec<&'a Target>,
+    ) -> SmResult<()> {
+        if result.iter().any(|t| t.name == target.name) {
+            return Ok(());
+        }
+        if visiting.contains(&target.name.as_str()) {
+            return Err(SmError::config(
+                "target.deps",
+                format!("Target {} has a cyclic dependency", target.name),
+            ));
+        }
+        visiting.push(&target.name);
+        for d in &target.deps {
+            match self.find_target(d) {
+                Some(dep) => self.visit_deps(dep, visiting, result)?,
+                None => {
+                    return Err(SmError::config(
+                        "target.deps",
+                        format!("Target {} depends on unknown target {}", target.name, d),
+                    ))
                 }
-                _ => {}
             }
         }
+        visiting.pop();
+        result.push(target);
+        Ok(())
+    }
+    //检查c++标准是否正确
+    pub fn check_std(&self) -> bool {
+        self.complier.std.name().is_some()
+    }
+    //检查c标准是否正确
+    pub fn check_c_std(&self) -> bool {
+        matches!(self.complier.c_std, None | Some(89 | 99 | 11 | 17 | 23))
+    }
+    //检查代码优化等级是否正确
+    pub fn check_ol(&self) -> bool {
+        matches!(self.complier.ol, 0..=3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    const COMPLIER: &str = "[complier]\nstd=17\nwall=true\nol=2\nlink=[]\nextra=[]\n";
+
+    //每个目标写成(名字, 依赖)
+    fn project(targets: &[(&str, &[&str])]) -> Project {
+        let mut content = String::new();
+        for (name, deps) in targets {
+            content.push_str(&format!(
+                "[[target]]\nname=\"{}\"\nkind=\"static\"\ndeps={:?}\n",
+                name, deps
+            ));
+        }
+        content.push_str(COMPLIER);
+        toml::from_str(&content).unwrap()
+    }
+
+    fn names(targets: Vec<&Target>) -> Vec<&str> {
+        targets.iter().map(|t| t.name.as_str()).collect()
     }
-    //获取需要编译的源文件数组
-    pub fn get_src_files(&self) -> Vec<PathBuf> {
-        let mut result: Vec<PathBuf> = Vec::new();
-        //获取当前目录
-        let mut current_path = env::current_dir().unwrap();
-        current_path.push(self.target.src.clone());
-        self.visit_dirs(&current_path, &mut result);
-        result
+
+    fn config_error<T>(result: SmResult<T>) -> (String, String) {
+        match result {
+            Err(SmError::Config { field, message }) => (field, message),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected a config error"),
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_target_names() {
+        let p = project(&[("a", &[]), ("b", &[]), ("a", &[])]);
+        let (field, message) = config_error(p.validate());
+        assert_eq!(field, "target.name");
+        assert!(message.contains("Target a"), "{}", message);
+    }
+
+    #[test]
+    fn resolves_dependencies_before_dependents() {
+        let p = project(&[
+            ("app", &["net", "log"]),
+            ("net", &["log"]),
+            ("log", &[]),
+            ("other", &[]),
+        ]);
+        assert!(p.validate().is_ok());
+        assert_eq!(
+            names(p.resolve(Some("app")).unwrap()),
+            ["log", "net", "app"]
+        );
+        assert_eq!(names(p.resolve(Some("log")).unwrap()), ["log"]);
+        //所有目标都只出现一次
+        assert_eq!(
+            names(p.resolve(None).unwrap()),
+            ["log", "net", "app", "other"]
+        );
+    }
+
+    #[test]
+    fn reports_cyclic_and_unknown_dependencies() {
+        let p = project(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        let (field, message) = config_error(p.resolve(Some("a")));
+        assert_eq!(field, "target.deps");
+        assert!(message.contains("cyclic"), "{}", message);
+
+        let p = project(&[("a", &["a"])]);
+        assert_eq!(config_error(p.resolve(None)).0, "target.deps");
+
+        let p = project(&[("a", &["b"]), ("b", &["missing"])]);
+        let (field, message) = config_error(p.resolve(Some("a")));
+        assert_eq!(field, "target.deps");
+        assert_eq!(message, "Target b depends on unknown target missing");
+
+        match project(&[("a", &[])]).resolve(Some("missing")) {
+            Err(SmError::Usage(m)) => assert_eq!(m, "Target missing does not exist"),
+            _ => panic!("unknown target accepted"),
+        }
+    }
+
+    //临时目录里的一个小项目，包含各种扩展名的文件、子目录和隐藏目录
+    fn source_tree(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("sm-config-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        for f in [
+            "src/main.cpp",
+            "src/a.cc",
+            "src/b.c",
+            "src/x.hpp",
+            "src/y.h",
+            "src/notes.txt",
+            "src/net/tcp.cpp",
+            "src/net/udp.cxx",
+            "src/legacy/old.cpp",
+            "src/.hidden/h.cpp",
+        ] {
+            let path = root.join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        root
+    }
+
+    //target为[[target]]里除了name之外的内容，返回相对路径
+    fn src_files(root: &Path, target: &str) -> Vec<String> {
+        let target: Target = toml::from_str(&format!("name=\"t\"\n{}", target)).unwrap();
+        target
+            .get_src_files(root)
+            .unwrap()
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn walks_src_in_sorted_order_without_headers() {
+        let root = source_tree("walk");
+        assert_eq!(
+            src_files(&root, "src=\"src\""),
+            [
+                "src/a.cc",
+                "src/b.c",
+                "src/legacy/old.cpp",
+                "src/main.cpp",
+                "src/net/tcp.cpp",
+                "src/net/udp.cxx"
+            ]
+        );
+        //写了sources时头文件和不认识的扩展名也不会被编译
+        assert_eq!(
+            src_files(&root, "sources=[\"src/*\"]"),
+            ["src/a.cc", "src/b.c", "src/main.cpp"]
+        );
+        assert_eq!(
+            src_files(&root, "src=\"src\"\nextensions=[\".cpp\"]"),
+            ["src/legacy/old.cpp", "src/main.cpp", "src/net/tcp.cpp"]
+        );
+    }
+
+    #[test]
+    fn single_star_stays_in_one_directory() {
+        let root = source_tree("star");
+        assert_eq!(
+            src_files(&root, "sources=[\"src/*.cpp\"]"),
+            ["src/main.cpp"]
+        );
+        assert_eq!(
+            src_files(&root, "sources=[\"src/**/*.cpp\"]"),
+            ["src/legacy/old.cpp", "src/main.cpp", "src/net/tcp.cpp"]
+        );
+        //两个glob匹配到同一个文件时只出现一次
+        assert_eq!(
+            src_files(&root, "sources=[\"src/net/*\", \"src/**/tcp.cpp\"]"),
+            ["src/net/tcp.cpp", "src/net/udp.cxx"]
+        );
+    }
+
+    #[test]
+    fn exclude_applies_to_src_and_sources() {
+        let root = source_tree("exclude");
+        assert_eq!(
+            src_files(
+                &root,
+                "src=\"src\"\nexclude=[\"src/legacy/**\", \"**/*.c\"]"
+            ),
+            [
+                "src/a.cc",
+                "src/main.cpp",
+                "src/net/tcp.cpp",
+                "src/net/udp.cxx"
+            ]
+        );
+        assert_eq!(
+            src_files(&root, "sources=[\"src/**/*.cpp\"]\nexclude=[\"src/net/*\"]"),
+            ["src/legacy/old.cpp", "src/main.cpp"]
+        );
+    }
+
+    #[test]
+    fn glob_base_stops_at_the_first_wildcard() {
+        assert_eq!(glob_base("src/**/*.cpp"), PathBuf::from("src"));
+        assert_eq!(glob_base("a/b/c?.cpp"), PathBuf::from("a/b"));
+        assert_eq!(glob_base("src/{a,b}/x.cpp"), PathBuf::from("src"));
+        assert_eq!(glob_base("src/[ab].cpp"), PathBuf::from("src"));
+        assert_eq!(glob_base("*.cpp"), PathBuf::new());
+        assert_eq!(glob_base("src/main.cpp"), PathBuf::from("src/main.cpp"));
+    }
+
+    #[test]
+    fn invalid_globs_name_the_field() {
+        let (field, message) =
+            config_error(build_globset("target.exclude", &["src/[".to_string()]));
+        assert_eq!(field, "target.exclude");
+        assert!(message.contains("src/["), "{}", message);
     }
 }
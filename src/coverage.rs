@@ -0,0 +1,427 @@
+/*
+sm coverage用到的覆盖率工具，GCC使用--coverage和gcov，Clang使用基于源码的覆盖率，
+两种数据都整理成每个文件每一行的执行次数，再生成lcov和HTML报告
+*/
+
+use crate::command::{relative_to, OneLineCommand};
+use crate::error::{SmError, SmResult};
+use crate::probe;
+use crate::toolchain::{Family, Tool, Toolchain};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//插桩需要的编译和链接参数
+pub fn flags(family: Family) -> SmResult<Vec<String>> {
+    let flags: &[&str] = match family {
+        Family::Gcc => &["--coverage"],
+        Family::Clang => &["-fprofile-instr-generate", "-fcoverage-mapping"],
+        Family::Unknown => {
+            return Err(SmError::config(
+                "complier.cxx",
+                "Coverage needs a GCC or Clang complier".to_string(),
+            ))
+        }
+    };
+    Ok(flags.iter().map(|f| f.to_string()).collect())
+}
+
+//每个文件每一行的执行次数
+#[derive(Default)]
+pub struct Coverage {
+    pub files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+//运行测试之前删掉上次的数据，GCC的计数会累加，Clang的数据文件会越来越多
+pub fn prepare(toolchain: &Toolchain, sm_dir: &Path) -> SmResult<()> {
+    match toolchain.family {
+        Family::Gcc => {
+            for f in find_files(sm_dir, "gcda") {
+                fs::remove_file(&f).map_err(|e| SmError::io(&f, e))?;
+            }
+        }
+        _ => {
+            let dir = sm_dir.join("profraw");
+            if dir.exists() {
+                fs::remove_dir_all(&dir).map_err(|e| SmError::io(&dir, e))?;
+            }
+            //测试程序从环境变量里读取数据文件的位置，%p为进程号
+            env::set_var("LLVM_PROFILE_FILE", dir.join("%p-%m.profraw"));
+        }
+    }
+    Ok(())
+}
+
+//收集测试运行之后的数据，objects为Clang需要的测试程序和动态库
+pub fn collect(toolchain: &Toolchain, sm_dir: &Path, objects: &[PathBuf]) -> SmResult<Coverage> {
+    match toolchain.family {
+        Family::Gcc => collect_gcov(&toolchain.cxx, sm_dir),
+        _ => collect_llvm(&toolchain.cxx, sm_dir, objects),
+    }
+}
+
+#[derive(Deserialize)]
+struct GcovOutput {
+    #[serde(default)]
+    current_working_directory: String,
+    files: Vec<GcovFile>,
+}
+
+#[derive(Deserialize)]
+struct GcovFile {
+    file: String,
+    lines: Vec<GcovLine>,
+}
+
+#[derive(Deserialize)]
+struct GcovLine {
+    line_number: u32,
+    count: u64,
+}
+
+//对每个.gcno运行gcov，没有运行过的目标文件没有.gcda，gcov会把执行次数当作0
+fn collect_gcov(cxx: &Tool, sm_dir: &Path) -> SmResult<Coverage> {
+    let gcov = sibling(cxx, "gcov");
+    let mut coverage = Coverage::default();
+    for gcno in find_files(sm_dir, "gcno") {
+        let mut cmd = OneLineCommand::new(&gcov);
+        cmd.args(["--json-format", "--stdout"]).arg(&gcno);
+        let bytes = match cmd.stdout_bytes() {
+            Some(b) => b,
+            None => return Err(SmError::Build(format!("Excuting {} Failed", cmd.display()))),
+        };
+        //每个数据文件输出一行JSON
+        for line in String::from_utf8_lossy(&bytes).lines() {
+            let output: GcovOutput = match serde_json::from_str(line) {
+                Ok(o) => o,
+                Err(_) => continue,
+            };
+            let cwd = Path::new(&output.current_working_directory);
+            for f in output.files {
+                let counts = coverage
+                    .files
+                    .entry(cwd.join(&f.file).to_string_lossy().into_owned())
+                    .or_default();
+                for l in f.lines {
+                    *counts.entry(l.line_number).or_default() += l.count;
+                }
+            }
+        }
+    }
+    Ok(coverage)
+}
+
+//合并所有的.profraw，再用llvm-cov导出成lcov格式
+fn collect_llvm(cxx: &Tool, sm_dir: &Path, objects: &[PathBuf]) -> SmResult<Coverage> {
+    let raw = find_files(&sm_dir.join("profraw"), "profraw");
+    let objects: Vec<&PathBuf> = objects.iter().filter(|o| o.is_file()).collect();
+    if raw.is_empty() || objects.is_empty() {
+        return Ok(Coverage::default());
+    }
+    let profdata = sm_dir.join("coverage.profdata");
+    let mut merge = OneLineCommand::new(sibling(cxx, "llvm-profdata"));
+    merge
+        .args(["merge", "-sparse"])
+        .args(&raw)
+        .arg("-o")
+        .arg(&profdata);
+    let (code, text) = merge.output();
+    if code != Some(0) {
+        return Err(SmError::Build(format!(
+            "{}\n{}",
+            merge.display(),
+            text.trim_end()
+        )));
+    }
+    let mut export = OneLineCommand::new(sibling(cxx, "llvm-cov"));
+    export
+        .args(["export", "-format=lcov"])
+        .arg(format!("-instr-profile={}", profdata.display()))
+        .arg(objects[0]);
+    for o in &objects[1..] {
+        export.arg("-object").arg(o);
+    }
+    match export.stdout_bytes() {
+        Some(b) => Ok(parse_lcov(&String::from_utf8_lossy(&b))),
+        None => Err(SmError::Build(format!(
+            "Excuting {} Failed",
+            export.display()
+        ))),
+    }
+}
+
+//只读取lcov里的SF和DA两种记录
+fn parse_lcov(text: &str) -> Coverage {
+    let mut coverage = Coverage::default();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        if let Some(file) = line.strip_prefix("SF:") {
+            current = Some(file.to_string());
+        } else if let (Some(da), Some(file)) = (line.strip_prefix("DA:"), &current) {
+            let mut parts = da.split(',');
+            let number = parts.next().and_then(|n| n.parse().ok());
+            let count: Option<u64> = parts.next().and_then(|n| n.parse().ok());
+            if let (Some(number), Some(count)) = (number, count) {
+                *coverage
+                    .files
+                    .entry(file.clone())
+                    .or_default()
+                    .entry(number)
+                    .or_default() += count;
+            }
+        } else if line == "end_of_record" {
+            current = None;
+        }
+    }
+    coverage
+}
+
+impl Coverage {
+    //只保留项目里dirs下面的文件，路径换成相对于项目根目录的
+    pub fn restrict(self, root: &Path, dirs: &[PathBuf]) -> Coverage {
+        let mut files: BTreeMap<String, BTreeMap<u32, u64>> = BTreeMap::new();
+        for (file, counts) in self.files {
+            let rel = relative_to(Path::new(&file), root);
+            let path = Path::new(&rel);
+            if path.is_relative() && dirs.iter().any(|d| path.starts_with(d)) {
+                let merged = files.entry(rel).or_default();
+                for (line, count) in counts {
+                    *merged.entry(line).or_default() += count;
+                }
+            }
+        }
+        Coverage { files }
+    }
+    //所有文件的总行数和执行过的行数
+    pub fn totals(&self) -> (usize, usize) {
+        self.files.values().fold((0, 0), |(lines, hit), counts| {
+            let (l, h) = line_counts(counts);
+            (lines + l, hit + h)
+        })
+    }
+    //打印每个文件的覆盖率
+    pub fn print_summary(&self) {
+        let width = self
+            .files
+            .keys()
+            .map(|f| f.len())
+            .chain(["File".len(), "TOTAL".len()])
+            .max()
+            .unwrap_or_default();
+        println!(
+            "{:<width$}  {:>7}  {:>7}  {:>7}",
+            "File", "Lines", "Missed", "Cover"
+        );
+        for (file, counts) in &self.files {
+            let (lines, hit) = line_counts(counts);
+            println!(
+                "{:<width$}  {:>7}  {:>7}  {:>7}",
+                file,
+                lines,
+                lines - hit,
+                percent(hit, lines)
+            );
+        }
+        let (lines, hit) = self.totals();
+        println!(
+            "{:<width$}  {:>7}  {:>7}  {:>7}",
+            "TOTAL",
+            lines,
+            lines - hit,
+            percent(hit, lines)
+        );
+    }
+    //写入lcov格式的数据，genhtml和大多数CI服务都能读取
+    pub fn write_lcov(&self, path: &Path, root: &Path) -> SmResult<()> {
+        let mut out = String::new();
+        for (file, counts) in &self.files {
+            let _ = writeln!(out, "SF:{}", root.join(file).display());
+            for (line, count) in counts {
+                let _ = writeln!(out, "DA:{},{}", line, count);
+            }
+            let (lines, hit) = line_counts(counts);
+            let _ = writeln!(out, "LH:{}\nLF:{}\nend_of_record", hit, lines);
+        }
+        fs::write(path, out).map_err(|e| SmError::io(path, e))
+    }
+    //写入HTML报告，index.html是汇总表，每个文件一个页面，没有执行过的行标红
+    pub fn write_html(&self, dir: &Path, root: &Path) -> SmResult<()> {
+        if dir.exists() {
+            fs::remove_dir_all(dir).map_err(|e| SmError::io(dir, e))?;
+        }
+        fs::create_dir_all(dir).map_err(|e| SmError::io(dir, e))?;
+        let mut rows = String::new();
+        for (file, counts) in &self.files {
+            let (lines, hit) = line_counts(counts);
+            let page = format!("{}.html", file.replace(['/', '\\'], "_"));
+            let _ = writeln!(
+                rows,
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                html_escape(&page),
+                html_escape(file),
+                lines,
+                lines - hit,
+                percent(hit, lines)
+            );
+            let source = fs::read_to_string(root.join(file)).unwrap_or_default();
+            let mut body = String::new();
+            for (i, text) in source.lines().enumerate() {
+                let (class, count) = match counts.get(&(i as u32 + 1)) {
+                    Some(0) => ("miss", "0".to_string()),
+                    Some(c) => ("hit", c.to_string()),
+                    None => ("", String::new()),
+                };
+                let _ = writeln!(
+                    body,
+                    "<tr class=\"{}\"><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>",
+                    class,
+                    i + 1,
+                    count,
+                    html_escape(text)
+                );
+            }
+            let title = format!("{} - {}", html_escape(file), percent(hit, lines));
+            let path = dir.join(&page);
+            fs::write(&path, html_page(&title, &body)).map_err(|e| SmError::io(&path, e))?;
+        }
+        let (lines, hit) = self.totals();
+        let _ = writeln!(
+            rows,
+            "<tr><th>TOTAL</th><th>{}</th><th>{}</th><th>{}</th></tr>",
+            lines,
+            lines - hit,
+            percent(hit, lines)
+        );
+        let table = format!(
+            "<tr><th>File</th><th>Lines</th><th>Missed</th><th>Cover</th></tr>\n{}",
+            rows
+        );
+        let path = dir.join("index.html");
+        fs::write(&path, html_page("Coverage", &table)).map_err(|e| SmError::io(&path, e))
+    }
+}
+
+//总行数和执行过的行数
+fn line_counts(counts: &BTreeMap<u32, u64>) -> (usize, usize) {
+    (counts.len(), counts.values().filter(|&&c| c > 0).count())
+}
+
+fn percent(hit: usize, lines: usize) -> String {
+    match lines {
+        0 => "-".to_string(),
+        _ => format!("{:.1}%", hit as f64 * 100.0 / lines as f64),
+    }
+}
+
+fn html_page(title: &str, table: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title>\n\
+         <style>body{{font-family:sans-serif}}table{{border-collapse:collapse}}\
+         td,th{{padding:0 8px;text-align:left}}pre{{margin:0}}\
+         .hit{{background:#dfd}}.miss{{background:#fdd}}</style></head>\n\
+         <body><h1>{0}</h1>\n<table>\n{1}</table></body></html>\n",
+        title, table
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+//和编译器配套的工具，比如g++-12对应gcov-12，找不到时使用不带前后缀的名字
+fn sibling(cxx: &Tool, to: &str) -> String {
+    let name = cxx.args.last().unwrap_or(&cxx.program);
+    for from in ["clang++", "g++", "c++"] {
+        if let Some(i) = name.rfind(from) {
+            let tool = format!("{}{}{}", &name[..i], to, &name[i + from.len()..]);
+            if probe::find_program(&tool).is_some() {
+                return tool;
+            }
+        }
+    }
+    to.to_string()
+}
+
+//递归查找某个扩展名的所有文件
+fn find_files(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return files,
+    };
+    for e in entries.flatten() {
+        let path = e.path();
+        if path.is_dir() {
+            files.extend(find_files(&path, ext));
+        } else if path.extension().is_some_and(|e| e == ext) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //llvm-cov export -format=lcov 的输出
+    const LLVM_LCOV: &str = "SF:/tmp/multi/core/src/core.cpp
+FN:3,_Z4corev
+FNDA:1,_Z4corev
+FNF:1
+FNH:1
+DA:3,1
+DA:4,0
+BRF:0
+BRH:0
+LF:2
+LH:1
+end_of_record
+SF:/tmp/multi/tests/test_core.cpp
+FN:2,main
+FNDA:1,main
+FNF:1
+FNH:1
+DA:2,1
+LF:1
+LH:1
+end_of_record
+SF:/usr/include/c++/12/bits/stl_vector.h
+DA:100,7
+end_of_record
+";
+
+    #[test]
+    fn parses_lcov_line_counts() {
+        let c = parse_lcov(LLVM_LCOV);
+        assert_eq!(c.files.len(), 3);
+        let core = &c.files["/tmp/multi/core/src/core.cpp"];
+        assert_eq!(core.iter().collect::<Vec<_>>(), [(&3, &1), (&4, &0)]);
+        assert_eq!(c.totals(), (4, 3));
+    }
+
+    #[test]
+    fn merges_repeated_records_and_ignores_checksums() {
+        let text = "SF:a.cpp\nDA:1,2,Zm9v\nend_of_record\nDA:9,9\nSF:a.cpp\nDA:1,3\nDA:x,1\nend_of_record\n";
+        let c = parse_lcov(text);
+        assert_eq!(c.files.len(), 1);
+        assert_eq!(c.files["a.cpp"].iter().collect::<Vec<_>>(), [(&1, &5)]);
+    }
+
+    #[test]
+    fn restricts_to_project_source_dirs() {
+        let c = parse_lcov(LLVM_LCOV).restrict(
+            Path::new("/tmp/multi"),
+            &[PathBuf::from("core/src"), PathBuf::from("core/inc")],
+        );
+        assert_eq!(c.files.keys().collect::<Vec<_>>(), ["core/src/core.cpp"]);
+        assert_eq!(c.totals(), (2, 1));
+    }
+}
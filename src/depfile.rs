@@ -0,0 +1,128 @@
+/*
+解析编译器通过-MMD -MF生成的Makefile格式依赖文件，
+拿到每个编译单元实际包含的头文件
+*/
+
+use crate::state::hash_file;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+//读取依赖文件，返回所有依赖的路径，文件不存在时返回None
+pub fn read_depfile(path: &Path) -> Option<Vec<String>> {
+    let content = fs::read_to_string(path).ok()?;
+    Some(parse_depfile(&content))
+}
+
+//解析依赖文件内容，格式类似于 a.o: a.cpp inc/a.hpp \
+pub fn parse_depfile(content: &str) -> Vec<String> {
+    let mut deps: Vec<String> = Vec::new();
+    //先把续行符去掉，拼成一整行
+    let content = content.replace("\\\r\n", " ").replace("\\\n", " ");
+    for line in content.lines() {
+        //跳过目标部分，冒号后面才是依赖
+        let rest = match find_colon(line) {
+            Some(i) => &line[i + 1..],
+            None => continue,
+        };
+        let mut word = String::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                //转义的空格和井号属于路径的一部分
+                '\\' if matches!(chars.peek(), Some(' ') | Some('#')) => {
+                    word.push(chars.next().unwrap());
+                }
+                '$' if chars.peek() == Some(&'$') => {
+                    word.push(chars.next().unwrap());
+                }
+                c if c.is_whitespace() => {
+                    if !word.is_empty() {
+                        deps.push(std::mem::take(&mut word));
+                    }
+                }
+                c => word.push(c),
+            }
+        }
+        if !word.is_empty() {
+            deps.push(word);
+        }
+    }
+    deps
+}
+
+//找到分隔目标和依赖的冒号，需要跳过类似C:\这样的盘符
+fn find_colon(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        if b != b':' {
+            continue;
+        }
+        let drive = i == 1 && bytes[0].is_ascii_alphabetic();
+        let next_is_slash = matches!(bytes.get(i + 1), Some(b'\\') | Some(b'/'));
+        if !(drive && next_is_slash) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+//计算所有依赖的摘要，依赖文件丢失时返回None，调用者需要强制重新编译
+pub fn hash_deps(deps: &[String]) -> Option<BTreeMap<String, String>> {
+    let mut result = BTreeMap::new();
+    for d in deps {
+        result.insert(d.clone(), hash_file(Path::new(d))?);
+    }
+    Some(result)
+}
+
+//检查记录下来的依赖是否都没有变化
+pub fn deps_are_fresh(deps: &BTreeMap<String, String>) -> bool {
+    deps.iter()
+        .all(|(d, h)| hash_file(Path::new(d)).as_ref() == Some(h))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_escaped_spaces_hashes_and_dollars() {
+        //g++ -c main.cpp -o main.o "-Imy inc" -MMD -MF main.d
+        let content = "main.o: main.cpp my\\ inc/a\\ b.hpp my\\ inc/c$$d.hpp my\\ inc/h\\#1.hpp\n";
+        assert_eq!(
+            parse_depfile(content),
+            [
+                "main.cpp",
+                "my inc/a b.hpp",
+                "my inc/c$d.hpp",
+                "my inc/h#1.hpp"
+            ]
+        );
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        let content = "/tmp/demo/.sm/obj/demo/src/main.cpp.o: /tmp/demo/src/main.cpp \\\n /tmp/demo/inc/add.hpp \\\r\n /tmp/demo/inc/sub.hpp\n";
+        assert_eq!(
+            parse_depfile(content),
+            [
+                "/tmp/demo/src/main.cpp",
+                "/tmp/demo/inc/add.hpp",
+                "/tmp/demo/inc/sub.hpp"
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_windows_drive_colons() {
+        let content = "C:\\proj\\obj\\a.o: C:\\proj\\src\\a.cpp C:/proj/inc/a.h\n";
+        assert_eq!(find_colon(content), Some(15));
+        assert_eq!(
+            parse_depfile(content),
+            ["C:\\proj\\src\\a.cpp", "C:/proj/inc/a.h"]
+        );
+        assert_eq!(find_colon("a.o: a.cpp"), Some(3));
+        assert_eq!(find_colon("no colon here"), None);
+    }
+}
@@ -0,0 +1,410 @@
+/*
+解析GCC和Clang输出的诊断信息，格式为 file:line:column: severity: message，
+用于统一的高亮显示、按文件统计，以及生成SARIF或者JSON报告
+*/
+
+use crate::error::{SmError, SmResult};
+use ansi_rgb::{cyan, green, magenta, red, Foreground};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+//报告的格式
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Sarif,
+    Json,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Sarif => "sarif",
+            Format::Json => "json",
+        }
+    }
+}
+
+//一条诊断信息，note附在它前面的错误或者警告上
+#[derive(Serialize, Clone)]
+pub struct Diagnostic {
+    //没有位置的诊断信息为程序名，比如<command-line>、cc1plus、collect2
+    pub file: String,
+    //没有位置时为0
+    pub line: u32,
+    //没有列号时为0
+    pub column: u32,
+    //error、warning、note或者remark，fatal error也算作error
+    pub severity: String,
+    pub message: String,
+    //相关的选项，比如-Wunused-variable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub option: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<Diagnostic>,
+}
+
+const SEVERITIES: [&str; 5] = ["fatal error", "error", "warning", "note", "remark"];
+
+//解析编译器的输出，不认识的行直接忽略，前面没有错误或者警告的note也忽略
+pub fn parse(text: &str) -> Vec<Diagnostic> {
+    let mut result: Vec<Diagnostic> = Vec::new();
+    for line in text.lines() {
+        let d = match parse_line(line) {
+            Some(d) => d,
+            None => continue,
+        };
+        match result.last_mut() {
+            Some(last) if d.severity == "note" => last.notes.push(d),
+            None if d.severity == "note" => {}
+            _ => result.push(d),
+        }
+    }
+    result
+}
+
+//解析一行，失败时返回None
+fn parse_line(line: &str) -> Option<Diagnostic> {
+    let (i, severity) = find_severity(line)?;
+    let (file, line_no, column) = split_location(&line[..i])?;
+    let start = i + format!(": {}: ", severity).len();
+    let mut message = line[start..].trim().to_string();
+    //GCC和Clang都会在消息末尾写上相关的选项，比如[-Wunused-variable]
+    let mut option = None;
+    if let Some(start) = message.rfind(" [-") {
+        if message.ends_with(']') {
+            option = Some(message[start + 2..message.len() - 1].to_string());
+            message.truncate(start);
+        }
+    }
+    let severity = if severity == "fatal error" {
+        "error"
+    } else {
+        severity
+    };
+    Some(Diagnostic {
+        file,
+        line: line_no,
+        column,
+        severity: severity.to_string(),
+        message,
+        option,
+        notes: Vec::new(),
+    })
+}
+
+//找到 ": error: " 这样的标记，返回位置和原始的级别，
+//消息里也可能出现": error: "这样的文字，以最靠前的为准
+fn find_severity(line: &str) -> Option<(usize, &'static str)> {
+    SEVERITIES
+        .iter()
+        .filter_map(|s| line.find(&format!(": {}: ", s)).map(|i| (i, *s)))
+        .min_by_key(|(i, _)| *i)
+}
+
+//拆分 file:line:column 或者 file:line，文件名里可能有盘符的冒号，所以从右边开始拆，
+//<command-line>、cc1plus这样没有行号的整体当作文件名，行号为0
+fn split_location(loc: &str) -> Option<(String, u32, u32)> {
+    if loc.is_empty() || loc.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (rest, last) = match loc.rsplit_once(':') {
+        Some((rest, last)) if !rest.is_empty() => match last.parse::<u32>() {
+            Ok(n) => (rest, n),
+            Err(_) => return Some((loc.to_string(), 0, 0)),
+        },
+        _ => return Some((loc.to_string(), 0, 0)),
+    };
+    match rest.rsplit_once(':') {
+        Some((file, line)) if !file.is_empty() => match line.parse() {
+            Ok(line) => Some((file.to_string(), line, last)),
+            Err(_) => Some((rest.to_string(), last, 0)),
+        },
+        _ => Some((rest.to_string(), last, 0)),
+    }
+}
+
+//给编译器的输出加上颜色，只给严重程度和指示位置的^~上色，其他内容保持原样
+pub fn highlight(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for line in text.lines() {
+        match parse_line(line) {
+            Some(d) => {
+                //fatal error整个上色，不改成error，只替换解析时找到的那一处
+                let (i, original) = find_severity(line).unwrap_or_default();
+                let colored = match d.severity.as_str() {
+                    "error" => original.fg(red()).to_string(),
+                    "warning" => original.fg(magenta()).to_string(),
+                    _ => original.fg(cyan()).to_string(),
+                };
+                let end = i + original.len() + 2;
+                result.push_str(&line[..i + 2]);
+                result.push_str(&colored);
+                result.push_str(&line[end..]);
+            }
+            None if is_caret_line(line) => result.push_str(&line.fg(green()).to_string()),
+            None => result.push_str(line),
+        }
+        result.push('\n');
+    }
+    result
+}
+
+//类似于 "      |     ^~~~" 这样指示位置的行
+fn is_caret_line(line: &str) -> bool {
+    match line.split_once('|') {
+        Some((left, right)) => {
+            left.trim().is_empty()
+                && right.contains('^')
+                && right.chars().all(|c| matches!(c, ' ' | '^' | '~'))
+        }
+        None => false,
+    }
+}
+
+//按文件统计错误和警告的个数
+pub fn count_by_file(diagnostics: &[Diagnostic]) -> BTreeMap<&str, (usize, usize)> {
+    let mut counts: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for d in diagnostics {
+        let entry = counts.entry(&d.file).or_default();
+        match d.severity.as_str() {
+            "error" => entry.0 += 1,
+            "warning" => entry.1 += 1,
+            _ => {}
+        }
+    }
+    counts
+}
+
+//写入报告文件
+pub fn write_report(
+    path: &Path,
+    format: Format,
+    diagnostics: &[Diagnostic],
+    root: &Path,
+) -> SmResult<()> {
+    let value = match format {
+        Format::Sarif => to_sarif(diagnostics, root),
+        Format::Json => json!(diagnostics),
+    };
+    let content = serde_json::to_string_pretty(&value)
+        .map_err(|e| SmError::Build(format!("Can't serialize diagnostics: {}", e)))?;
+    fs::write(path, content + "\n").map_err(|e| SmError::io(path, e))
+}
+
+//转换成SARIF 2.1.0，项目里的文件使用相对于SRCROOT的路径
+fn to_sarif(diagnostics: &[Diagnostic], root: &Path) -> serde_json::Value {
+    //没有行号的诊断信息只有消息，没有位置
+    let location = |d: &Diagnostic| {
+        if d.line == 0 {
+            return json!({});
+        }
+        let path = Path::new(&d.file);
+        let artifact = match path.strip_prefix(root) {
+            Ok(rel) => json!({"uri": rel.to_string_lossy(), "uriBaseId": "SRCROOT"}),
+            Err(_) if path.is_absolute() => {
+                json!({"uri": format!("file://{}", path.display())})
+            }
+            Err(_) => json!({"uri": d.file, "uriBaseId": "SRCROOT"}),
+        };
+        let mut region = json!({"startLine": d.line});
+        if d.column > 0 {
+            region["startColumn"] = json!(d.column);
+        }
+        json!({"physicalLocation": {"artifactLocation": artifact, "region": region}})
+    };
+    let results: Vec<serde_json::Value> = diagnostics
+        .iter()
+        .map(|d| {
+            let mut result = json!({
+                "level": match d.severity.as_str() {
+                    "error" => "error",
+                    "warning" => "warning",
+                    _ => "note",
+                },
+                "message": {"text": d.message},
+            });
+            if d.line > 0 {
+                result["locations"] = json!([location(d)]);
+            }
+            if let Some(option) = &d.option {
+                result["ruleId"] = json!(option);
+            }
+            if !d.notes.is_empty() {
+                let related: Vec<serde_json::Value> = d
+                    .notes
+                    .iter()
+                    .map(|n| {
+                        let mut l = location(n);
+                        l["message"] = json!({"text": n.message});
+                        l
+                    })
+                    .collect();
+                result["relatedLocations"] = json!(related);
+            }
+            result
+        })
+        .collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {"driver": {"name": "sm", "version": env!("CARGO_PKG_VERSION")}},
+            "originalUriBaseIds": {"SRCROOT": {"uri": format!("file://{}/", root.display())}},
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //g++ -Wall -c a.cpp -o a.o
+    const GCC_OUTPUT: &str = r#"a.cpp: In function 'int main()':
+a.cpp:4:14: error: invalid conversion from 'const char*' to 'int' [-fpermissive]
+    4 |     return f("x");
+      |              ^~~
+      |              |
+      |              const char*
+a.cpp:1:7: note:   initializing argument 1 of 'int f(int)'
+    1 | int f(int);
+      |       ^~~
+a.cpp:3:9: warning: unused variable 'unused' [-Wunused-variable]
+    3 |     int unused = 1;
+      |         ^~~~~~
+"#;
+
+    #[test]
+    fn attaches_notes_to_the_previous_error() {
+        let diags = parse(GCC_OUTPUT);
+        assert_eq!(diags.len(), 2);
+        let error = &diags[0];
+        assert_eq!(
+            (error.file.as_str(), error.line, error.column),
+            ("a.cpp", 4, 14)
+        );
+        assert_eq!(error.severity, "error");
+        assert_eq!(
+            error.message,
+            "invalid conversion from 'const char*' to 'int'"
+        );
+        assert_eq!(error.option.as_deref(), Some("-fpermissive"));
+        assert_eq!(error.notes.len(), 1);
+        assert_eq!(error.notes[0].line, 1);
+        assert_eq!(
+            error.notes[0].message,
+            "initializing argument 1 of 'int f(int)'"
+        );
+        let warning = &diags[1];
+        assert_eq!(warning.severity, "warning");
+        assert_eq!(warning.option.as_deref(), Some("-Wunused-variable"));
+        assert!(warning.notes.is_empty());
+    }
+
+    #[test]
+    fn treats_fatal_errors_and_command_line_errors_as_errors() {
+        //g++ -D1FOO -c b.cpp
+        let output = r#"<command-line>: error: macro names must be identifiers
+b.cpp:1:10: fatal error: missing.h: No such file or directory
+    1 | #include "missing.h"
+      |          ^~~~~~~~~~~
+compilation terminated.
+"#;
+        let diags = parse(output);
+        assert_eq!(diags.len(), 2);
+        assert_eq!(
+            (diags[0].file.as_str(), diags[0].line, diags[0].column),
+            ("<command-line>", 0, 0)
+        );
+        assert_eq!(diags[0].severity, "error");
+        assert_eq!(diags[1].severity, "error");
+        assert_eq!(diags[1].message, "missing.h: No such file or directory");
+        let counts = count_by_file(&diags);
+        assert_eq!(counts["<command-line>"], (1, 0));
+        assert_eq!(counts["b.cpp"], (1, 0));
+    }
+
+    #[test]
+    fn drops_notes_without_a_previous_diagnostic() {
+        let output = "cc1plus: note: someone else's note\n\
+                      <command-line>: error: macro names must be identifiers\n\
+                      cc1plus: note: self-tests are not enabled in this build\n";
+        let diags = parse(output);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].severity, "error");
+        assert_eq!(diags[0].notes.len(), 1);
+        assert_eq!(diags[0].notes[0].file, "cc1plus");
+    }
+
+    #[test]
+    fn splits_locations_from_the_right() {
+        assert_eq!(
+            split_location("src/a.cpp:12:5"),
+            Some(("src/a.cpp".to_string(), 12, 5))
+        );
+        assert_eq!(
+            split_location("src/a.cpp:12"),
+            Some(("src/a.cpp".to_string(), 12, 0))
+        );
+        assert_eq!(
+            split_location("C:\\proj\\a.cpp:3:7"),
+            Some(("C:\\proj\\a.cpp".to_string(), 3, 7))
+        );
+        assert_eq!(
+            split_location("C:\\proj\\a.cpp:3"),
+            Some(("C:\\proj\\a.cpp".to_string(), 3, 0))
+        );
+        assert_eq!(
+            split_location("<command-line>"),
+            Some(("<command-line>".to_string(), 0, 0))
+        );
+        assert_eq!(split_location(""), None);
+        assert_eq!(split_location("   4 | int x"), None);
+    }
+
+    #[test]
+    fn highlights_only_severities_and_carets() {
+        let text = highlight(GCC_OUTPUT);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), GCC_OUTPUT.lines().count());
+        assert_eq!(lines[0], "a.cpp: In function 'int main()':");
+        assert_eq!(
+            lines[1],
+            format!(
+                "a.cpp:4:14: {}: invalid conversion from 'const char*' to 'int' [-fpermissive]",
+                "error".fg(red())
+            )
+        );
+        assert_eq!(lines[3], "      |              ^~~".fg(green()).to_string());
+        assert_eq!(lines[4], "      |              |");
+        assert!(lines[6].contains(&"note".fg(cyan()).to_string()));
+        assert!(lines[9].contains(&"warning".fg(magenta()).to_string()));
+        let fatal = highlight("b.cpp:1:10: fatal error: missing.h: No such file or directory");
+        assert!(fatal.contains(&format!(": {}: missing.h", "fatal error".fg(red()))));
+    }
+
+    #[test]
+    fn uses_the_first_severity_on_the_line() {
+        let line =
+            "a.cpp:3:1: warning: 'x: error: y' is deprecated: fatal error: no [-Wdeprecated]";
+        let d = parse_line(line).unwrap();
+        assert_eq!((d.file.as_str(), d.line, d.column), ("a.cpp", 3, 1));
+        assert_eq!(d.severity, "warning");
+        assert_eq!(d.message, "'x: error: y' is deprecated: fatal error: no");
+        assert_eq!(d.option.as_deref(), Some("-Wdeprecated"));
+        let d = parse_line("a.cpp:5:2: note: in 'a: warning: b'").unwrap();
+        assert_eq!(d.severity, "note");
+        //只给级别上色，消息里的文字保持原样
+        assert_eq!(
+            highlight(line),
+            format!(
+                "a.cpp:3:1: {}: 'x: error: y' is deprecated: fatal error: no [-Wdeprecated]\n",
+                "warning".fg(magenta())
+            )
+        );
+    }
+}
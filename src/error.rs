@@ -0,0 +1,251 @@
+/*
+统一的错误类型，每一类错误对应不同的退出码，
+main里打印出可读的错误信息，不再直接panic
+*/
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum SmError {
+    //命令行参数不对
+    Usage(String),
+    //读写文件或者文件夹失败
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    //project.toml语法错误，带有行号、列号和出错的键，比如target.src
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        key: Option<String>,
+        message: String,
+    },
+    //配置项的值不正确，field为出错的字段名
+    Config {
+        field: String,
+        message: String,
+    },
+    //编译、链接等命令执行失败
+    Build(String),
+}
+
+pub type SmResult<T> = Result<T, SmError>;
+
+impl SmError {
+    pub fn io(path: &Path, source: io::Error) -> SmError {
+        SmError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+    pub fn config(field: &str, message: String) -> SmError {
+        SmError::Config {
+            field: field.to_string(),
+            message,
+        }
+    }
+    //根据toml的错误位置求出行号和列号
+    pub fn parse(path: &Path, content: &str, e: toml::de::Error) -> SmError {
+        let offset = e.span().map(|s| s.start).unwrap_or(0).min(content.len());
+        let before = &content[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        SmError::Parse {
+            path: path.to_path_buf(),
+            line,
+            column,
+            key: key_path(content, offset),
+            message: e.message().trim_end().replace('\n', "; "),
+        }
+    }
+    //不同类型的错误使用不同的退出码
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SmError::Build(_) => 1,
+            SmError::Usage(_) => 2,
+            SmError::Parse { .. } | SmError::Config { .. } => 3,
+            SmError::Io { .. } => 4,
+        }
+    }
+}
+
+impl fmt::Display for SmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmError::Usage(m) => write!(f, "{}", m),
+            SmError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            SmError::Parse {
+                path,
+                line,
+                column,
+                key: Some(key),
+                message,
+            } => write!(
+                f,
+                "{}:{}:{}: `{}`: {}",
+                path.display(),
+                line,
+                column,
+                key,
+                message
+            ),
+            SmError::Parse {
+                path,
+                line,
+                column,
+                key: None,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            SmError::Config { field, message } => write!(f, "`{}`: {}", field, message),
+            SmError::Build(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+//toml的错误里拿不到键的路径，只能根据出错位置从原文里推出来：
+//先找所在的[table]或者[[table]]，再找同一个语句里offset之前的键
+fn key_path(content: &str, offset: usize) -> Option<String> {
+    let before = &content[..offset];
+    let mut path = Vec::new();
+    let mut start = 0;
+    //从后往前找表头
+    let lines: Vec<usize> = std::iter::once(0)
+        .chain(before.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    for &i in lines.iter().rev() {
+        let line = before[i..].lines().next().unwrap_or("");
+        if line.trim_start().starts_with('[') {
+            start = i + line.len();
+            path = table_name(line.trim());
+            break;
+        }
+    }
+    //(层数, 键)，新的键会替换同一层和更深层的键
+    let mut keys: Vec<(usize, String)> = Vec::new();
+    let mut depth: usize = 0;
+    let mut key_start = start;
+    let mut chars = before[start..].char_indices().map(|(i, c)| (i + start, c));
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                let mut escaped = false;
+                for (_, d) in chars.by_ref() {
+                    if c == '"' && d == '\\' && !escaped {
+                        escaped = true;
+                        continue;
+                    }
+                    if d == c && !escaped {
+                        break;
+                    }
+                    escaped = false;
+                }
+            }
+            '#' => {
+                for (_, d) in chars.by_ref() {
+                    if d == '\n' {
+                        break;
+                    }
+                }
+                if depth == 0 {
+                    keys.clear();
+                }
+                key_start = i + 1;
+            }
+            '{' | '[' => {
+                depth += 1;
+                key_start = i + 1;
+            }
+            '}' | ']' => {
+                depth = depth.saturating_sub(1);
+                keys.retain(|(d, _)| *d <= depth);
+            }
+            ',' => key_start = i + 1,
+            '\n' => {
+                if depth == 0 {
+                    keys.clear();
+                }
+                key_start = i + 1;
+            }
+            '=' => {
+                let key = before[key_start.min(i)..i].trim().replace(['"', '\''], "");
+                keys.retain(|(d, _)| *d < depth);
+                keys.push((depth, key));
+            }
+            _ => {}
+        }
+    }
+    path.extend(keys.into_iter().map(|(_, k)| k));
+    if path.is_empty() {
+        None
+    } else {
+        Some(path.join("."))
+    }
+}
+
+//[target]、[[target]]、[target.public]里的表名
+fn table_name(line: &str) -> Vec<String> {
+    line.trim_start_matches('[')
+        .split(']')
+        .next()
+        .unwrap_or("")
+        .split('.')
+        .map(|k| k.trim().replace(['"', '\''], ""))
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Project;
+
+    const COMPLIER: &str = "[complier]\nstd=17\nwall=true\nol=2\nlink=[]\nextra=[]\n";
+
+    fn parse_err(content: &str) -> SmError {
+        match toml::from_str::<Project>(content) {
+            Ok(_) => panic!("{} should not parse", content),
+            Err(e) => SmError::parse(Path::new("project.toml"), content, e),
+        }
+    }
+
+    #[test]
+    fn error_points_at_target_field() {
+        let content = format!("[target]\nname=\"a\"\ndefines=3\n{}", COMPLIER);
+        let e = parse_err(&content);
+        match &e {
+            SmError::Parse {
+                line, column, key, ..
+            } => {
+                assert_eq!((*line, *column), (3, 9));
+                assert_eq!(key.as_deref(), Some("target.defines"));
+            }
+            _ => panic!("not a parse error"),
+        }
+        assert!(e
+            .to_string()
+            .starts_with("project.toml:3:9: `target.defines`: "));
+    }
+
+    #[test]
+    fn error_names_target_field() {
+        let content = format!("[[target]]\nname=\"a\"\nsrc=3\n{}", COMPLIER);
+        let e = parse_err(&content);
+        assert!(e.to_string().contains("`target.src`"), "{}", e);
+        assert!(e.to_string().starts_with("project.toml:3:5:"), "{}", e);
+    }
+
+    #[test]
+    fn key_path_of_inline_tables() {
+        let content = "[target.public]\ndefines = { A = \"x\", B = [1, {c = ";
+        assert_eq!(
+            key_path(content, content.len()).as_deref(),
+            Some("target.public.defines.B.c")
+        );
+        let content = "# [x]\nname = \"a=b\" # c=d\n";
+        assert_eq!(key_path(content, content.len()), None);
+    }
+}
@@ -0,0 +1,39 @@
+/*
+--message-format json时输出的事件流，每行一个JSON对象，方便CI等工具解析，
+这个模式下不再输出给人看的彩色文字
+*/
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+//输出的格式
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+pub fn set_format(format: MessageFormat) {
+    JSON.store(format == MessageFormat::Json, Ordering::Relaxed);
+}
+
+//是否输出JSON事件
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+//输出一个事件，fields里的字段和事件类型放在同一个对象里
+pub fn emit(event: &str, fields: Value) {
+    let mut object = Map::new();
+    object.insert("event".to_string(), Value::from(event));
+    if let Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+    //整行一次写入，避免和其他输出交错
+    let mut out = io::stdout().lock();
+    let _ = writeln!(out, "{}", Value::Object(object));
+}
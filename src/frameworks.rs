@@ -0,0 +1,423 @@
+/*
+识别GoogleTest、Catch2和doctest的控制台输出，解析出每个测试用例的结果，
+生成报告时一个测试程序里的用例可以分别列出来
+*/
+
+use std::time::Duration;
+
+//测试框架
+#[derive(Clone, Copy, PartialEq)]
+pub enum Framework {
+    GoogleTest,
+    Catch2,
+    Doctest,
+}
+
+impl Framework {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Framework::GoogleTest => "googletest",
+            Framework::Catch2 => "catch2",
+            Framework::Doctest => "doctest",
+        }
+    }
+}
+
+//一个测试用例的结果
+#[derive(Clone)]
+pub struct CaseResult {
+    pub name: String,
+    pub passed: bool,
+    pub skipped: bool,
+    //GoogleTest会输出每个用例的耗时
+    pub duration: Option<Duration>,
+    //失败时的断言信息
+    pub message: String,
+}
+
+//解析的结果，Catch2和doctest只列出失败的用例，total为输出里统计的用例总数
+pub struct Parsed {
+    pub framework: Framework,
+    pub cases: Vec<CaseResult>,
+    pub total: usize,
+    pub failed: usize,
+}
+
+//认不出是哪个框架时返回None
+pub fn parse(output: &str) -> Option<Parsed> {
+    if output.contains("[doctest] test cases:") {
+        return Some(parse_doctest(output));
+    }
+    if output
+        .lines()
+        .any(|l| l.starts_with("[==========] Running "))
+    {
+        return Some(parse_gtest(output));
+    }
+    if output.contains("All tests passed (")
+        || (output.contains("host application.") && output.contains("test cases:"))
+    {
+        return Some(parse_catch2(output));
+    }
+    None
+}
+
+//[ RUN      ] Suite.Name 到 [       OK ] Suite.Name (0 ms) 之间是这个用例的输出
+fn parse_gtest(output: &str) -> Parsed {
+    let mut cases: Vec<CaseResult> = Vec::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for line in output.lines() {
+        if let Some(name) = line.strip_prefix("[ RUN      ] ") {
+            current = Some((name.trim().to_string(), Vec::new()));
+            continue;
+        }
+        let status = ["[       OK ] ", "[  FAILED  ] ", "[  SKIPPED ] "]
+            .iter()
+            .position(|p| line.starts_with(p));
+        match (&mut current, status) {
+            (Some(_), Some(s)) => {
+                let (name, lines) = current.take().unwrap_or_default();
+                cases.push(CaseResult {
+                    name,
+                    passed: s != 1,
+                    skipped: s == 2,
+                    duration: gtest_duration(line),
+                    message: lines.join("\n").trim().to_string(),
+                });
+            }
+            (Some((_, lines)), None) => lines.push(line),
+            _ => {}
+        }
+    }
+    //崩溃时最后一个用例没有结束行
+    if let Some((name, lines)) = current {
+        cases.push(CaseResult {
+            name,
+            passed: false,
+            skipped: false,
+            duration: None,
+            message: lines.join("\n").trim().to_string(),
+        });
+    }
+    let failed = cases.iter().filter(|c| !c.passed).count();
+    Parsed {
+        framework: Framework::GoogleTest,
+        total: cases.len(),
+        failed,
+        cases,
+    }
+}
+
+//结束行末尾的 (12 ms)
+fn gtest_duration(line: &str) -> Option<Duration> {
+    let inner = line.trim_end().strip_suffix(" ms)")?;
+    let ms: u64 = inner[inner.rfind('(')? + 1..].parse().ok()?;
+    Some(Duration::from_millis(ms))
+}
+
+//失败的用例夹在两行横线之间，后面是位置和一行点，然后是断言信息
+fn parse_catch2(output: &str) -> Parsed {
+    let lines: Vec<&str> = output.lines().collect();
+    let is_rule = |l: &str, c: char| l.len() >= 20 && l.chars().all(|x| x == c);
+    let mut cases: Vec<CaseResult> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if !is_rule(lines[i], '-') {
+            i += 1;
+            continue;
+        }
+        //用例名，嵌套的SECTION各占一行
+        let start = i + 1;
+        let mut end = start;
+        while end < lines.len() && !is_rule(lines[end], '-') {
+            end += 1;
+        }
+        if end >= lines.len() || start == end {
+            break;
+        }
+        let name = lines[start].trim().to_string();
+        //跳过位置和点线，直到下一个用例或者最后的统计
+        let mut j = end + 1;
+        while j < lines.len() && !is_rule(lines[j], '.') {
+            j += 1;
+        }
+        if j >= lines.len() {
+            break;
+        }
+        let body_start = j + 1;
+        let mut k = body_start;
+        while k < lines.len() && !is_rule(lines[k], '-') && !is_rule(lines[k], '=') {
+            k += 1;
+        }
+        let message = lines[body_start.min(k)..k].join("\n").trim().to_string();
+        add_failure(&mut cases, name, message);
+        i = k;
+    }
+    let (total, failed) = match output.lines().find_map(|l| l.strip_prefix("test cases:")) {
+        Some(summary) => summary_counts(summary),
+        //全部通过时只有一行 All tests passed (3 assertions in 2 test cases)
+        None => (catch2_passed(output).unwrap_or(0), 0),
+    };
+    Parsed {
+        framework: Framework::Catch2,
+        cases,
+        total,
+        failed,
+    }
+}
+
+fn catch2_passed(output: &str) -> Option<usize> {
+    let line = output
+        .lines()
+        .find(|l| l.starts_with("All tests passed ("))?;
+    let rest = &line[line.find(" in ")? + 4..];
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+//失败的用例以一行等号开始，接着是位置和 TEST CASE:  name
+fn parse_doctest(output: &str) -> Parsed {
+    let mut cases: Vec<CaseResult> = Vec::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for line in output.lines() {
+        if line.starts_with("=====") || line.starts_with("[doctest]") {
+            if let Some((name, lines)) = current.take() {
+                add_failure(&mut cases, name, lines.join("\n").trim().to_string());
+            }
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("TEST CASE:") {
+            current = Some((name.trim().to_string(), Vec::new()));
+            continue;
+        }
+        if let Some((_, lines)) = &mut current {
+            lines.push(line);
+        }
+    }
+    if let Some((name, lines)) = current {
+        add_failure(&mut cases, name, lines.join("\n").trim().to_string());
+    }
+    let (total, failed) = output
+        .lines()
+        .find_map(|l| l.strip_prefix("[doctest] test cases:"))
+        .map(summary_counts)
+        .unwrap_or((cases.len(), cases.len()));
+    Parsed {
+        framework: Framework::Doctest,
+        cases,
+        total,
+        failed,
+    }
+}
+
+//同一个用例的多个失败合并在一起
+fn add_failure(cases: &mut Vec<CaseResult>, name: String, message: String) {
+    match cases.iter_mut().find(|c| c.name == name) {
+        Some(c) => {
+            c.message.push_str("\n\n");
+            c.message.push_str(&message);
+        }
+        None => cases.push(CaseResult {
+            name,
+            passed: false,
+            skipped: false,
+            duration: None,
+            message,
+        }),
+    }
+}
+
+//解析 " 2 | 1 passed | 1 failed" 这样的统计，返回总数和失败数
+fn summary_counts(summary: &str) -> (usize, usize) {
+    let mut total = 0;
+    let mut failed = 0;
+    for (i, part) in summary.split('|').enumerate() {
+        let mut words = part.split_whitespace();
+        let n: usize = words.next().and_then(|w| w.parse().ok()).unwrap_or(0);
+        match (i, words.next()) {
+            (0, _) => total = n,
+            (_, Some("failed")) => failed = n,
+            _ => {}
+        }
+    }
+    (total, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //GoogleTest 1.11，最后一个用例调用了abort
+    const GTEST_CRASH: &str = "Running main() from gtest_main.cc
+[==========] Running 4 tests from 1 test suite.
+[----------] Global test environment set-up.
+[----------] 4 tests from Math
+[ RUN      ] Math.Add
+[       OK ] Math.Add (0 ms)
+[ RUN      ] Math.Sub
+g.cpp:4: Failure
+Expected equality of these values:
+  3 - 1
+    Which is: 2
+  1
+off by one
+[  FAILED  ] Math.Sub (0 ms)
+[ RUN      ] Math.Skip
+g.cpp:5: Skipped
+not ready
+[  SKIPPED ] Math.Skip (0 ms)
+[ RUN      ] Math.Crash
+";
+
+    #[test]
+    fn parses_gtest_cases_and_a_crash() {
+        let p = parse(GTEST_CRASH).unwrap();
+        assert!(p.framework == Framework::GoogleTest);
+        let names: Vec<&str> = p.cases.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["Math.Add", "Math.Sub", "Math.Skip", "Math.Crash"]);
+        assert!(p.cases[0].passed && !p.cases[0].skipped);
+        assert_eq!(p.cases[0].duration, Some(Duration::from_millis(0)));
+        assert!(!p.cases[1].passed);
+        assert!(p.cases[1].message.starts_with("g.cpp:4: Failure"));
+        assert!(p.cases[1].message.ends_with("off by one"));
+        assert!(p.cases[2].passed && p.cases[2].skipped);
+        assert!(!p.cases[3].passed);
+        assert_eq!(p.cases[3].duration, None);
+        assert_eq!((p.total, p.failed), (4, 2));
+    }
+
+    #[test]
+    fn ignores_the_gtest_summary_lists() {
+        let output = "[==========] Running 2 tests from 1 test suite.
+[ RUN      ] Math.Add
+[       OK ] Math.Add (12 ms)
+[ RUN      ] Math.Sub
+g.cpp:4: Failure
+[  FAILED  ] Math.Sub (0 ms)
+[==========] 2 tests from 1 test suite ran. (12 ms total)
+[  PASSED  ] 1 test.
+[  FAILED  ] 1 test, listed below:
+[  FAILED  ] Math.Sub
+
+ 1 FAILED TEST
+";
+        let p = parse(output).unwrap();
+        assert_eq!(p.cases.len(), 2);
+        assert_eq!(p.cases[0].duration, Some(Duration::from_millis(12)));
+        assert_eq!((p.total, p.failed), (2, 1));
+    }
+
+    #[test]
+    fn parses_catch2_failures_with_nested_sections() {
+        //Catch v2.13.9 --use-colour no
+        let output = "
+~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+c is a Catch v2.13.9 host application.
+Run with -? for options
+
+-------------------------------------------------------------------------------
+vectors can be sized
+  resizing bigger
+  twice
+-------------------------------------------------------------------------------
+c.cpp:7
+...............................................................................
+
+c.cpp:7: FAILED:
+  CHECK( size * 2 == 11 )
+with expansion:
+  10 == 11
+
+-------------------------------------------------------------------------------
+subtracts
+-------------------------------------------------------------------------------
+c.cpp:10
+...............................................................................
+
+c.cpp:10: FAILED:
+  REQUIRE( 3 - 1 == 1 )
+with expansion:
+  2 == 1
+
+===============================================================================
+test cases: 3 | 1 passed | 2 failed
+assertions: 3 | 1 passed | 2 failed
+
+";
+        let p = parse(output).unwrap();
+        assert!(p.framework == Framework::Catch2);
+        let names: Vec<&str> = p.cases.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["vectors can be sized", "subtracts"]);
+        assert!(p.cases.iter().all(|c| !c.passed));
+        assert_eq!(
+            p.cases[0].message,
+            "c.cpp:7: FAILED:\n  CHECK( size * 2 == 11 )\nwith expansion:\n  10 == 11"
+        );
+        assert!(p.cases[1].message.contains("REQUIRE( 3 - 1 == 1 )"));
+        assert_eq!((p.total, p.failed), (3, 2));
+    }
+
+    #[test]
+    fn parses_catch2_success() {
+        let output = "Filters: adds numbers
+===============================================================================
+All tests passed (1 assertion in 1 test case)
+
+";
+        let p = parse(output).unwrap();
+        assert!(p.framework == Framework::Catch2);
+        assert!(p.cases.is_empty());
+        assert_eq!((p.total, p.failed), (1, 0));
+    }
+
+    #[test]
+    fn parses_doctest_failures() {
+        //doctest 2.4 的控制台输出
+        let output = r#"[doctest] doctest version is "2.4.11"
+[doctest] run with "--help" for options
+===============================================================================
+t.cpp:8:
+TEST CASE:  subtracts
+
+t.cpp:9: ERROR: CHECK( 3 - 1 == 1 ) is NOT correct!
+  values: CHECK( 2 == 1 )
+
+===============================================================================
+t.cpp:12:
+TEST CASE:  vectors
+  resizing
+
+t.cpp:14: ERROR: CHECK( v.size() == 11 ) is NOT correct!
+  values: CHECK( 10 == 11 )
+
+===============================================================================
+[doctest] test cases: 3 | 1 passed | 2 failed | 0 skipped
+[doctest] assertions: 3 | 1 passed | 2 failed |
+[doctest] Status: FAILURE!
+"#;
+        let p = parse(output).unwrap();
+        assert!(p.framework == Framework::Doctest);
+        let names: Vec<&str> = p.cases.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["subtracts", "vectors"]);
+        assert!(p.cases[0]
+            .message
+            .starts_with("t.cpp:9: ERROR: CHECK( 3 - 1 == 1 ) is NOT correct!"));
+        assert!(p.cases[1].message.contains("CHECK( 10 == 11 )"));
+        assert_eq!((p.total, p.failed), (3, 2));
+    }
+
+    #[test]
+    fn counts_summary_columns() {
+        assert_eq!(summary_counts(" 3 | 1 passed | 2 failed"), (3, 2));
+        assert_eq!(
+            summary_counts(" 4 | 4 passed | 0 failed | 0 skipped"),
+            (4, 0)
+        );
+        assert_eq!(summary_counts(" 2 | 2 passed"), (2, 0));
+    }
+
+    #[test]
+    fn leaves_unknown_output_alone() {
+        assert!(parse("all good\n").is_none());
+        assert!(parse("").is_none());
+    }
+}
@@ -0,0 +1,657 @@
+/*
+构建图，每个步骤都有明确的输入和输出，
+一个步骤的输入如果是另一个步骤的输出，就要等那个步骤完成之后才能执行
+*/
+
+use crate::cache::ObjectCache;
+use crate::command::{print_output, relative_to, OneLineCommand, Verbosity};
+use crate::depfile::{hash_deps, read_depfile};
+use crate::diagnostic::{self, Diagnostic};
+use crate::error::{SmError, SmResult};
+use crate::events;
+use crate::state::{hash_file, hash_files, BuildState, StepRecord, UnitRecord};
+use ansi_rgb::{cyan_blue, green, red, Background};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+
+//步骤类型
+#[derive(Clone, Copy, PartialEq)]
+pub enum StepKind {
+    //把源文件编译成目标文件
+    Compile,
+    //把目标文件打包成静态库
+    Archive,
+    //把目标文件链接成动态库
+    SharedLink,
+    //链接可执行文件
+    ExeLink,
+    //project.toml里的[[step]]，执行任意命令
+    Custom,
+}
+
+impl StepKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StepKind::Compile => "compile",
+            StepKind::Archive => "archive",
+            StepKind::SharedLink => "shared-link",
+            StepKind::ExeLink => "exe-link",
+            StepKind::Custom => "custom",
+        }
+    }
+}
+
+//构建图中的一个步骤
+pub struct Step {
+    pub kind: StepKind,
+    //构建数据库里的键，编译步骤是源文件的相对路径，其他步骤是产物的相对路径
+    pub key: String,
+    pub cmd: OneLineCommand,
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    //自定义步骤除了output之外生成的其他文件
+    pub extra_outputs: Vec<PathBuf>,
+    //需要编译源文件的步骤才有依赖文件
+    pub depfile: Option<PathBuf>,
+    //使用内置缓存的编译步骤才有
+    pub cache: Option<Arc<ObjectCache>>,
+}
+
+impl Step {
+    pub fn new(
+        kind: StepKind,
+        key: String,
+        cmd: OneLineCommand,
+        inputs: Vec<PathBuf>,
+        output: PathBuf,
+    ) -> Step {
+        Step {
+            kind,
+            key,
+            cmd,
+            inputs,
+            output,
+            extra_outputs: Vec::new(),
+            depfile: None,
+            cache: None,
+        }
+    }
+    //生成的所有文件
+    pub fn outputs(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.output).chain(self.extra_outputs.iter())
+    }
+    //执行步骤，开启了内置缓存时先从缓存里找
+    fn execute(&self) -> (Option<i32>, String) {
+        //ar只会往已有的静态库里添加，先删掉旧的，已经删除的源文件才不会留在里面
+        if self.kind == StepKind::Archive {
+            let _ = fs::remove_file(&self.output);
+        }
+        match &self.cache {
+            Some(c) => c.compile(&self.cmd, &self.output),
+            None => self.cmd.output(),
+        }
+    }
+    //库和可执行文件生成之后输出事件，fresh表示没有重新生成
+    fn emit_artifact(&self, fresh: bool) {
+        if self.kind != StepKind::Compile {
+            events::emit(
+                "artifact-produced",
+                json!({
+                    "key": self.key,
+                    "kind": self.kind.name(),
+                    "path": self.output,
+                    "fresh": fresh,
+                }),
+            );
+        }
+    }
+    //编译步骤的记录，源文件缺失时返回None
+    fn unit_record(&self) -> Option<UnitRecord> {
+        Some(UnitRecord {
+            hash: hash_file(self.inputs.first()?)?,
+            command: self.cmd.display(),
+            object: self.output.to_string_lossy().into_owned(),
+            deps: BTreeMap::new(),
+        })
+    }
+    //其他步骤的记录，输入文件缺失时返回None
+    fn step_record(&self) -> Option<StepRecord> {
+        Some(StepRecord {
+            command: self.cmd.display(),
+            inputs: hash_files(&self.inputs)?,
+        })
+    }
+    //判断步骤是否可以跳过
+    fn is_fresh(&self, db: &BuildState) -> bool {
+        match self.kind {
+            StepKind::Compile => match self.unit_record() {
+                Some(r) => db.unit_is_fresh(&self.key, &r),
+                None => false,
+            },
+            _ => match self.step_record() {
+                Some(r) => {
+                    db.step_is_fresh(&self.key, &r, &self.output)
+                        && self.extra_outputs.iter().all(|p| p.is_file())
+                }
+                None => false,
+            },
+        }
+    }
+    //执行成功后写入构建数据库，依赖文件读不到时删除记录，下次构建会重新执行
+    fn record_success(&self, db: &mut BuildState) {
+        match self.kind {
+            StepKind::Compile => {
+                let deps = match &self.depfile {
+                    Some(d) => collect_deps(d),
+                    None => Some(BTreeMap::new()),
+                };
+                match (self.unit_record(), deps) {
+                    (Some(mut r), Some(deps)) => {
+                        r.deps = deps;
+                        db.units.insert(self.key.clone(), r);
+                    }
+                    _ => {
+                        db.units.remove(&self.key);
+                    }
+                }
+            }
+            _ => match self.step_record() {
+                Some(r) => {
+                    db.steps.insert(self.key.clone(), r);
+                }
+                None => {
+                    db.steps.remove(&self.key);
+                }
+            },
+        }
+    }
+    //执行失败的步骤要从数据库里删掉，保证下次一定重新执行
+    fn record_failure(&self, db: &mut BuildState) {
+        match self.kind {
+            StepKind::Compile => {
+                db.units.remove(&self.key);
+            }
+            _ => {
+                db.steps.remove(&self.key);
+            }
+        }
+    }
+}
+
+//一次构建的结果
+pub struct Outcome {
+    //执行失败的步骤
+    pub failed: Vec<String>,
+    //因为出错而没有执行的步骤数
+    pub skipped: usize,
+    //编译器输出的诊断信息
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+pub struct BuildGraph {
+    steps: Vec<Step>,
+    //项目根目录，诊断信息里项目内的文件使用相对路径
+    root: PathBuf,
+}
+
+impl BuildGraph {
+    pub fn new(root: PathBuf) -> BuildGraph {
+        BuildGraph {
+            steps: Vec::new(),
+            root,
+        }
+    }
+    //添加一个步骤，返回它的序号，两个步骤生成同一个文件时报错
+    pub fn add(&mut self, step: Step) -> SmResult<usize> {
+        for other in &self.steps {
+            if let Some(output) = step.outputs().find(|&p| other.outputs().any(|o| o == p)) {
+                return Err(SmError::Build(format!(
+                    "{} and {} both produce {}",
+                    other.key,
+                    step.key,
+                    relative_to(output, &self.root)
+                )));
+            }
+        }
+        self.steps.push(step);
+        Ok(self.steps.len() - 1)
+    }
+    //所有步骤
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+    //根据输入输出求出每个步骤依赖几个步骤，以及哪些步骤依赖它
+    fn edges(&self) -> (Vec<usize>, Vec<Vec<usize>>) {
+        let producers: HashMap<&Path, usize> = self
+            .steps
+            .iter()
+            .enumerate()
+            .flat_map(|(i, s)| s.outputs().map(move |o| (o.as_path(), i)))
+            .collect();
+        let mut remaining = vec![0; self.steps.len()];
+        let mut dependents = vec![Vec::new(); self.steps.len()];
+        for (i, step) in self.steps.iter().enumerate() {
+            let mut deps: Vec<usize> = step
+                .inputs
+                .iter()
+                .filter_map(|p| producers.get(p.as_path()).copied())
+                .collect();
+            deps.sort();
+            deps.dedup();
+            remaining[i] = deps.len();
+            for d in deps {
+                dependents[d].push(i);
+            }
+        }
+        (remaining, dependents)
+    }
+    //检查是否存在环，存在环的图没法调度
+    fn has_cycle(&self) -> bool {
+        let (mut remaining, dependents) = self.edges();
+        let mut ready: Vec<usize> = (0..self.steps.len())
+            .filter(|&i| remaining[i] == 0)
+            .collect();
+        let mut visited = 0;
+        while let Some(i) = ready.pop() {
+            visited += 1;
+            for &d in &dependents[i] {
+                remaining[d] -= 1;
+                if remaining[d] == 0 {
+                    ready.push(d);
+                }
+            }
+        }
+        visited != self.steps.len()
+    }
+    //按拓扑顺序执行所有步骤，最多同时执行jobs个，
+    //keep_going为true时出错之后继续执行不依赖失败步骤的步骤
+    pub fn run(
+        &self,
+        db: &mut BuildState,
+        jobs: usize,
+        verbosity: Verbosity,
+        keep_going: bool,
+    ) -> SmResult<Outcome> {
+        if self.has_cycle() {
+            return Err(SmError::Build(
+                "Build graph has a cycle, some steps depend on their own outputs".to_string(),
+            ));
+        }
+        let (mut remaining, dependents) = self.edges();
+        let has_deps: Vec<bool> = remaining.iter().map(|&r| r > 0).collect();
+        //没有前置步骤的步骤在开始前就能判断是否需要执行
+        let fresh: Vec<bool> = self
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| remaining[i] == 0 && s.is_fresh(db))
+            .collect();
+        let skipped = fresh.iter().filter(|&&f| f).count();
+        //JSON模式下只输出事件
+        let json = events::is_json();
+        if json {
+            for (i, step) in self.steps.iter().enumerate() {
+                if fresh[i] {
+                    step.emit_artifact(true);
+                }
+            }
+        } else if verbosity == Verbosity::Verbose {
+            for (i, step) in self.steps.iter().enumerate() {
+                if fresh[i] {
+                    println!("{}: {}", "Fresh".bg(green()), step.key);
+                }
+            }
+        } else if skipped > 0 && verbosity == Verbosity::Normal {
+            println!("{} steps are up to date.", skipped);
+        }
+        let length = self.steps.len() - skipped;
+        let mut ready: VecDeque<usize> = (0..self.steps.len())
+            .filter(|&i| remaining[i] == 0)
+            .collect();
+        let mut index = 0;
+        let mut running = 0;
+        let mut completed = 0;
+        //失败的步骤以及它的第一条诊断信息
+        let mut failures: Vec<(usize, String)> = Vec::new();
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            loop {
+                //出错之后不再调度新的步骤，但要等正在执行的步骤结束
+                while (failures.is_empty() || keep_going) && running < jobs.max(1) {
+                    let i = match ready.pop_front() {
+                        Some(i) => i,
+                        None => break,
+                    };
+                    let step = &self.steps[i];
+                    let done = if fresh[i] {
+                        true
+                    } else if has_deps[i] && step.is_fresh(db) {
+                        //有前置步骤的要等前置步骤完成后才能判断
+                        index += 1;
+                        let header = format!("[{}/{}]", index, length);
+                        if json {
+                            step.emit_artifact(true);
+                        } else if verbosity > Verbosity::Quiet {
+                            println!(
+                                "{}: {} is up to date.",
+                                header.bg(cyan_blue()),
+                                step.output.display().to_string().bg(green())
+                            );
+                        }
+                        true
+                    } else {
+                        false
+                    };
+                    if done {
+                        completed += 1;
+                        finish(i, &dependents, &mut remaining, &mut ready);
+                        continue;
+                    }
+                    running += 1;
+                    if json {
+                        events::emit(
+                            "step-started",
+                            json!({
+                                "key": step.key,
+                                "kind": step.kind.name(),
+                                "command": step.cmd.argv(),
+                            }),
+                        );
+                    }
+                    let tx = tx.clone();
+                    scope.spawn(move || {
+                        let start = Instant::now();
+                        let result = step.execute();
+                        let _ = tx.send((i, result, start.elapsed()));
+                    });
+                }
+                if running == 0 {
+                    break;
+                }
+                //按完成顺序整块打印输出，避免日志交错
+                let (i, (code, text), duration) = rx.recv().unwrap();
+                running -= 1;
+                index += 1;
+                let ok = code == Some(0);
+                let step = &self.steps[i];
+                let mut parsed = diagnostic::parse(&text);
+                for d in &mut parsed {
+                    d.file = relative_to(Path::new(&d.file), &self.root);
+                    for n in &mut d.notes {
+                        n.file = relative_to(Path::new(&n.file), &self.root);
+                    }
+                }
+                if json {
+                    for d in &parsed {
+                        let mut fields = json!(d);
+                        fields["key"] = json!(step.key);
+                        events::emit("diagnostic", fields);
+                    }
+                    events::emit(
+                        "step-finished",
+                        json!({
+                            "key": step.key,
+                            "kind": step.kind.name(),
+                            "success": ok,
+                            "exit_code": code,
+                            "duration_ms": duration.as_millis() as u64,
+                            "output": text,
+                        }),
+                    );
+                    if ok {
+                        step.emit_artifact(false);
+                    }
+                } else {
+                    let header = format!("[{}/{}]", index, length);
+                    //安静模式下只打印失败的命令
+                    if verbosity > Verbosity::Quiet || !ok {
+                        println!("{}: {}", header.bg(cyan_blue()), step.cmd.display());
+                    }
+                    //能解析出诊断信息时只高亮关键部分，否则失败的输出整块标红
+                    if parsed.is_empty() && !ok {
+                        print_output(&text.as_str().bg(red()).to_string());
+                    } else {
+                        print_output(&diagnostic::highlight(&text));
+                    }
+                }
+                diagnostics.extend(parsed);
+                if ok {
+                    completed += 1;
+                    step.record_success(db);
+                    finish(i, &dependents, &mut remaining, &mut ready);
+                } else {
+                    step.record_failure(db);
+                    failures.push((i, first_diagnostic(&text)));
+                }
+            }
+        });
+        //依赖失败步骤的步骤不会执行，最后统一列出所有失败的步骤
+        let skipped = self.steps.len() - completed - failures.len();
+        if keep_going && !failures.is_empty() && !json {
+            println!("{} steps failed:", failures.len());
+            for (i, diagnostic) in &failures {
+                println!(
+                    "  {}: {}",
+                    self.steps[*i].key.as_str().bg(red()),
+                    diagnostic
+                );
+            }
+            if skipped > 0 {
+                println!("{} steps were skipped because of the failures.", skipped);
+            }
+        }
+        Ok(Outcome {
+            failed: failures
+                .into_iter()
+                .map(|(i, _)| self.steps[i].key.clone())
+                .collect(),
+            skipped,
+            diagnostics,
+        })
+    }
+}
+
+//步骤完成后，把所有前置步骤都已完成的后续步骤放入就绪队列
+fn finish(
+    i: usize,
+    dependents: &[Vec<usize>],
+    remaining: &mut [usize],
+    ready: &mut VecDeque<usize>,
+) {
+    for &d in &dependents[i] {
+        remaining[d] -= 1;
+        if remaining[d] == 0 {
+            ready.push_back(d);
+        }
+    }
+}
+
+//输出里的第一条错误信息，没有时使用第一行，去掉终端颜色
+fn first_diagnostic(text: &str) -> String {
+    let plain = strip_ansi(text);
+    let lines: Vec<&str> = plain.lines().filter(|l| !l.trim().is_empty()).collect();
+    lines
+        .iter()
+        .find(|l| l.contains("error"))
+        .or(lines.first())
+        .map(|l| l.trim().to_string())
+        .unwrap_or_default()
+}
+
+//去掉ANSI转义序列
+fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            //跳过参数，直到结束字母为止
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            continue;
+        }
+        result.push(c);
+    }
+    result
+}
+
+//读取依赖文件并计算所有头文件的摘要，失败时返回None
+fn collect_deps(depfile: &Path) -> Option<BTreeMap<String, String>> {
+    match read_depfile(depfile) {
+        Some(deps) => hash_deps(&deps),
+        None => {
+            eprintln!("Can't read dependency file {}", depfile.display());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    //每个测试一个临时目录，log里按执行顺序记录步骤名
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("sm-graph-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    //成功的步骤记下自己的名字并生成同名的文件，slow开头的先等一会，失败的步骤执行false
+    fn step(dir: &Path, name: &str, ok: bool, inputs: &[&str]) -> Step {
+        let script = match ok {
+            true if name.starts_with("slow") => {
+                format!("sleep 0.5; echo {0} >> log && touch {0}", name)
+            }
+            true => format!("echo {0} >> log && touch {0}", name),
+            false => "false".to_string(),
+        };
+        let mut cmd = OneLineCommand::new("sh");
+        cmd.arg("-c")
+            .arg(format!("cd '{}' && {}", dir.display(), script));
+        Step::new(
+            StepKind::Custom,
+            name.to_string(),
+            cmd,
+            inputs.iter().map(|i| dir.join(i)).collect(),
+            dir.join(name),
+        )
+    }
+
+    fn graph(dir: &Path, steps: Vec<Step>) -> BuildGraph {
+        let mut graph = BuildGraph::new(dir.to_path_buf());
+        for s in steps {
+            graph.add(s).unwrap();
+        }
+        graph
+    }
+
+    fn log(dir: &Path) -> Vec<String> {
+        fs::read_to_string(dir.join("log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    fn run(graph: &BuildGraph, db: &mut BuildState, jobs: usize, keep_going: bool) -> Outcome {
+        graph.run(db, jobs, Verbosity::Quiet, keep_going).unwrap()
+    }
+
+    #[test]
+    fn runs_steps_in_topological_order() {
+        let dir = temp_dir("topo");
+        //故意倒着添加，执行顺序只取决于输入输出
+        let g = graph(
+            &dir,
+            vec![
+                step(&dir, "link", true, &["a", "b"]),
+                step(&dir, "a", true, &["gen"]),
+                step(&dir, "b", true, &["gen"]),
+                step(&dir, "gen", true, &[]),
+            ],
+        );
+        let mut db = BuildState::default();
+        let outcome = run(&g, &mut db, 4, false);
+        assert!(outcome.failed.is_empty());
+        assert_eq!(outcome.skipped, 0);
+        let order = log(&dir);
+        let pos = |n: &str| order.iter().position(|l| l == n).unwrap();
+        assert_eq!(order.len(), 4);
+        assert_eq!(pos("gen"), 0);
+        assert_eq!(pos("link"), 3);
+        //第二次什么都不用做
+        run(&g, &mut db, 4, false);
+        assert_eq!(log(&dir).len(), 4);
+    }
+
+    #[test]
+    fn stops_scheduling_after_a_failure_but_waits_for_running_steps() {
+        let dir = temp_dir("stop");
+        let g = graph(
+            &dir,
+            vec![
+                step(&dir, "bad", false, &[]),
+                step(&dir, "slow", true, &[]),
+                step(&dir, "later", true, &[]),
+                step(&dir, "after", true, &["slow"]),
+            ],
+        );
+        let mut db = BuildState::default();
+        let outcome = run(&g, &mut db, 2, false);
+        assert_eq!(outcome.failed, vec!["bad".to_string()]);
+        assert_eq!(outcome.skipped, 2);
+        //已经开始执行的slow会执行完并写入数据库，之后不再调度新的步骤
+        assert_eq!(log(&dir), vec!["slow".to_string()]);
+        assert!(db.steps.contains_key("slow"));
+        assert!(!db.steps.contains_key("bad"));
+    }
+
+    #[test]
+    fn keep_going_only_skips_dependents_of_failures() {
+        let dir = temp_dir("keep-going");
+        let g = graph(
+            &dir,
+            vec![
+                step(&dir, "bad", false, &[]),
+                step(&dir, "needs-bad", true, &["bad"]),
+                step(&dir, "link", true, &["needs-bad", "ok"]),
+                step(&dir, "ok", true, &[]),
+                step(&dir, "needs-ok", true, &["ok"]),
+                step(&dir, "worse", false, &["ok"]),
+            ],
+        );
+        let mut db = BuildState::default();
+        let outcome = run(&g, &mut db, 1, true);
+        assert_eq!(outcome.failed, vec!["bad".to_string(), "worse".to_string()]);
+        assert_eq!(outcome.skipped, 2);
+        assert_eq!(log(&dir), vec!["ok".to_string(), "needs-ok".to_string()]);
+    }
+
+    #[test]
+    fn rejects_two_steps_with_the_same_output() {
+        let dir = temp_dir("duplicate");
+        let mut g = BuildGraph::new(dir.clone());
+        g.add(step(&dir, "a", true, &[])).unwrap();
+        match g.add(step(&dir, "a", true, &["b"])) {
+            Err(SmError::Build(m)) => assert_eq!(m, "a and a both produce a"),
+            _ => panic!("duplicate output accepted"),
+        }
+        //自定义步骤的其他输出也算
+        let mut custom = step(&dir, "gen", true, &[]);
+        custom.extra_outputs.push(dir.join("a"));
+        assert!(g.add(custom).is_err());
+        assert_eq!(g.steps().len(), 1);
+    }
+}
@@ -1,7 +1,39 @@
 mod args;
+mod cache;
 mod command;
+mod compdb;
 mod config;
+mod coverage;
+mod depfile;
+mod diagnostic;
+mod error;
+mod events;
+mod frameworks;
+mod graph;
+mod probe;
+mod report;
+mod state;
+mod testing;
+mod toolchain;
+
+use ansi_rgb::{red, Background};
+use std::process;
 
 fn main() {
-    args::read_console_input();
+    //不给用户看Rust的调用栈，只打印一行内部错误
+    std::panic::set_hook(Box::new(|info| {
+        eprintln!("{}", format!("internal error: {}", info).bg(red()));
+    }));
+    match args::read_console_input() {
+        Ok(code) => process::exit(code),
+        //JSON模式下不输出颜色
+        Err(e) if events::is_json() => {
+            eprintln!("error: {}", e);
+            process::exit(e.exit_code());
+        }
+        Err(e) => {
+            eprintln!("{}", format!("error: {}", e).bg(red()));
+            process::exit(e.exit_code());
+        }
+    }
 }
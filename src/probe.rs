@@ -0,0 +1,191 @@
+/*
+运行编译器检查它的家族、版本以及支持的C++标准，
+结果缓存在.sm/probe.toml里，编译器没有变化时不再重复检查
+*/
+
+use crate::config::Standard;
+use crate::error::{SmError, SmResult};
+use crate::toolchain::{Family, Tool};
+use duct::cmd;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+//检查结果的缓存，键里带有编译器的身份
+pub struct ProbeCache {
+    path: PathBuf,
+    entries: BTreeMap<String, String>,
+}
+
+impl ProbeCache {
+    pub fn load(sm_dir: &Path) -> ProbeCache {
+        let path = sm_dir.join("probe.toml");
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| toml::from_str(&c).ok())
+            .unwrap_or_default();
+        ProbeCache { path, entries }
+    }
+    //有缓存时直接使用，没有时执行检查并写回缓存
+    fn get_or_probe<F>(&mut self, key: String, probe: F) -> SmResult<String>
+    where
+        F: FnOnce() -> SmResult<String>,
+    {
+        if let Some(v) = self.entries.get(&key) {
+            return Ok(v.clone());
+        }
+        let value = probe()?;
+        self.entries.insert(key, value.clone());
+        //缓存写不进去只是下次再检查一遍
+        if let Ok(content) = toml::to_string(&self.entries) {
+            let _ = fs::write(&self.path, content);
+        }
+        Ok(value)
+    }
+}
+
+//根据预定义的宏判断编译器的家族和版本，clang也会定义__GNUC__，所以先检查它
+pub fn identify(cache: &mut ProbeCache, cxx: &Tool) -> SmResult<(Family, String)> {
+    let key = format!("{} identify", tool_identity(cxx));
+    let value = cache.get_or_probe(key, || {
+        let output = cmd(
+            &cxx.program,
+            with_args(cxx, &["-dM", "-E", "-x", "c++", "-"]),
+        )
+        .stdin_bytes("")
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .map_err(|e| SmError::io(Path::new(&cxx.program), e))?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        let mut macros: BTreeMap<&str, &str> = BTreeMap::new();
+        for line in text.lines() {
+            let mut words = line.splitn(3, ' ');
+            if let (Some("#define"), Some(name), Some(value)) =
+                (words.next(), words.next(), words.next())
+            {
+                macros.insert(name, value);
+            }
+        }
+        let version = |names: [&str; 3]| {
+            names
+                .iter()
+                .map(|n| macros.get(n).copied().unwrap_or("0"))
+                .collect::<Vec<_>>()
+                .join(".")
+        };
+        let (family, version) = if macros.contains_key("__clang__") {
+            (
+                Family::Clang,
+                version(["__clang_major__", "__clang_minor__", "__clang_patchlevel__"]),
+            )
+        } else if macros.contains_key("__GNUC__") {
+            (
+                Family::Gcc,
+                version(["__GNUC__", "__GNUC_MINOR__", "__GNUC_PATCHLEVEL__"]),
+            )
+        } else {
+            (Family::Unknown, String::new())
+        };
+        Ok(format!("{} {}", family.name(), version))
+    })?;
+    let (family, version) = value.split_once(' ').unwrap_or((&value, ""));
+    Ok((Family::from_name(family), version.to_string()))
+}
+
+//求出编译C++需要的-std参数，老版本编译器只认识草案名字时使用草案名字
+pub fn cxx_std_flag(cache: &mut ProbeCache, cxx: &Tool, std: &Standard) -> SmResult<String> {
+    let name = match std.name() {
+        Some(n) => n,
+        None => {
+            return Err(SmError::config(
+                "complier.std",
+                format!("C++ standard {} is invaild", std),
+            ))
+        }
+    };
+    let key = format!("{} {}", tool_identity(cxx), name);
+    let flag = cache.get_or_probe(key, || {
+        for n in std::iter::once(name.clone()).chain(std.draft_name()) {
+            if supports(cxx, &n)? {
+                return Ok(format!("-std={}", n));
+            }
+        }
+        Ok(String::new())
+    })?;
+    if flag.is_empty() {
+        return Err(SmError::config(
+            "complier.std",
+            format!(
+                "{} does not support C++ standard {}, please upgrade it or use an older standard",
+                cxx, std
+            ),
+        ));
+    }
+    Ok(flag)
+}
+
+//用空的输入试着编译一次
+fn supports(cxx: &Tool, name: &str) -> SmResult<bool> {
+    let flag = format!("-std={}", name);
+    let args = with_args(cxx, &[&flag, "-x", "c++", "-fsyntax-only", "-"]);
+    let output = cmd(&cxx.program, args)
+        .stdin_bytes("")
+        .stdout_null()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .map_err(|e| SmError::io(Path::new(&cxx.program), e))?;
+    Ok(output.status.success())
+}
+
+//工具自带的参数后面再加上检查用的参数
+fn with_args(tool: &Tool, extra: &[&str]) -> Vec<String> {
+    let mut args = tool.args.clone();
+    args.extend(extra.iter().map(|a| a.to_string()));
+    args
+}
+
+//工具的身份，每个能找到的可执行文件都带上路径、大小和修改时间，
+//这些都没变就认为还是同一个编译器
+pub fn tool_identity(tool: &Tool) -> String {
+    std::iter::once(&tool.program)
+        .chain(tool.args.iter())
+        .map(|w| file_identity(w))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn file_identity(name: &str) -> String {
+    let path = match find_program(name) {
+        Some(p) => p,
+        None => return name.to_string(),
+    };
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    match fs::metadata(&path) {
+        Ok(m) => {
+            let mtime = m
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            format!("{}:{}:{}", path.display(), m.len(), mtime)
+        }
+        Err(_) => path.display().to_string(),
+    }
+}
+
+//在PATH里查找程序，带有路径分隔符时直接使用
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let p = Path::new(name);
+    if p.components().count() > 1 {
+        return p.is_file().then(|| p.to_path_buf());
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|f| f.is_file())
+}
@@ -0,0 +1,372 @@
+/*
+sm test的结果报告，JUnit XML给CI的看板使用，TAP给其他测试工具使用，
+认出了测试框架的测试程序，里面的每个用例分别列出来
+*/
+
+use crate::error::{SmError, SmResult};
+use crate::frameworks::CaseResult;
+use crate::testing::TestResult;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReportKind {
+    Junit,
+    Tap,
+}
+
+pub struct Report {
+    pub kind: ReportKind,
+    pub path: PathBuf,
+}
+
+impl Report {
+    //解析 junit=path.xml 或者 tap 这样的参数，不写路径时放在dir下面
+    pub fn parse(spec: &str, dir: &Path) -> SmResult<Report> {
+        let (name, path) = match spec.split_once('=') {
+            Some((n, p)) => (n, Some(PathBuf::from(p))),
+            None => (spec, None),
+        };
+        let (kind, default) = match name {
+            "junit" => (ReportKind::Junit, "test-results.xml"),
+            "tap" => (ReportKind::Tap, "test-results.tap"),
+            _ => {
+                return Err(SmError::Usage(format!(
+                    "Unknown report format {}, expected junit or tap",
+                    name
+                )))
+            }
+        };
+        Ok(Report {
+            kind,
+            path: path.unwrap_or_else(|| dir.join(default)),
+        })
+    }
+    pub fn write(&self, results: &[TestResult]) -> SmResult<()> {
+        let content = match self.kind {
+            ReportKind::Junit => junit(results),
+            ReportKind::Tap => tap(results),
+        };
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| SmError::io(dir, e))?;
+        }
+        fs::write(&self.path, content).map_err(|e| SmError::io(&self.path, e))
+    }
+}
+
+//一个测试程序对应一个testsuite，没有认出框架时只有一个和程序同名的testcase，
+//Catch2和doctest只列出失败的用例，所以tests按实际写出的testcase计数
+fn junit(results: &[TestResult]) -> String {
+    let tests: usize = results.iter().map(|r| cases(r).len()).sum();
+    let failures: usize = results.iter().map(failure_count).sum();
+    let time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites name=\"sm\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        tests, failures, time
+    );
+    for r in results {
+        let cases = cases(r);
+        let skipped = cases.iter().filter(|c| c.skipped).count();
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            xml_escape(&r.name),
+            cases.len(),
+            failure_count(r),
+            skipped,
+            r.duration.as_secs_f64()
+        );
+        for c in &cases {
+            let time = c.duration.unwrap_or(if cases.len() == 1 {
+                r.duration
+            } else {
+                Default::default()
+            });
+            let _ = write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                xml_escape(&c.name),
+                xml_escape(&r.name),
+                time.as_secs_f64()
+            );
+            if c.skipped {
+                xml.push_str(">\n      <skipped/>\n    </testcase>\n");
+            } else if !c.passed {
+                let summary = c.message.lines().next().unwrap_or_default();
+                let _ = write!(
+                    xml,
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                    xml_escape(summary),
+                    xml_escape(&c.message)
+                );
+            } else {
+                xml.push_str("/>\n");
+            }
+        }
+        if !r.stdout.is_empty() {
+            let _ = writeln!(
+                xml,
+                "    <system-out>{}</system-out>",
+                xml_escape(&r.stdout)
+            );
+        }
+        if !r.stderr.is_empty() {
+            let _ = writeln!(
+                xml,
+                "    <system-err>{}</system-err>",
+                xml_escape(&r.stderr)
+            );
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+//TAP version 13，每个测试程序一行，详细信息放在YAML块里
+fn tap(results: &[TestResult]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", results.len());
+    for (i, r) in results.iter().enumerate() {
+        let status = if r.passed { "ok" } else { "not ok" };
+        let _ = writeln!(out, "{} {} - {}", status, i + 1, r.name);
+        out.push_str("  ---\n");
+        let _ = writeln!(out, "  duration_ms: {}", r.duration.as_millis());
+        match r.code {
+            Some(c) => {
+                let _ = writeln!(out, "  exit_code: {}", c);
+            }
+            None => out.push_str("  exit_code: ~\n"),
+        }
+        if let Some(p) = &r.parsed {
+            let _ = writeln!(out, "  framework: {}", p.framework.name());
+            let failed: Vec<&CaseResult> = p.cases.iter().filter(|c| !c.passed).collect();
+            let _ = writeln!(out, "  tests: {}", p.total.max(p.cases.len()));
+            let _ = writeln!(out, "  failed: {}", p.failed.max(failed.len()));
+            if !failed.is_empty() {
+                out.push_str("  failures:\n");
+                for c in failed {
+                    let _ = writeln!(out, "    - name: {}", yaml_quote(&c.name));
+                    yaml_block(&mut out, "      message", &c.message);
+                }
+            }
+        }
+        yaml_block(&mut out, "  stdout", &r.stdout);
+        yaml_block(&mut out, "  stderr", &r.stderr);
+        out.push_str("  ...\n");
+    }
+    out
+}
+
+//报告里列出的用例，测试程序失败但是没有解析出失败的用例时，补上一个和程序同名的用例
+fn cases(r: &TestResult) -> Vec<CaseResult> {
+    let mut cases: Vec<CaseResult> = r.parsed.as_ref().map_or(Vec::new(), |p| p.cases.clone());
+    if cases.is_empty() || (!r.passed && cases.iter().all(|c| c.passed)) {
+        let message = match r.code {
+            _ if r.passed => String::new(),
+            Some(c) => format!("exit code {}", c),
+            None => "terminated without an exit code".to_string(),
+        };
+        cases.push(CaseResult {
+            name: r.name.clone(),
+            passed: r.passed,
+            skipped: false,
+            duration: None,
+            message,
+        });
+    }
+    cases
+}
+
+//写出的用例里失败的个数
+fn failure_count(r: &TestResult) -> usize {
+    cases(r).iter().filter(|c| !c.passed).count()
+}
+
+//转义XML的特殊字符，去掉XML里不允许出现的控制字符
+fn xml_escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            '\t' | '\n' | '\r' => result.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+//多行文本写成YAML的块，为空时不写
+fn yaml_block(out: &mut String, key: &str, text: &str) {
+    if text.trim().is_empty() {
+        return;
+    }
+    let indent = " ".repeat(key.len() - key.trim_start().len() + 2);
+    let _ = writeln!(out, "{}: |", key);
+    //YAML里也不能出现控制字符
+    for line in text.lines() {
+        let line: String = line.chars().filter(|&c| c == '\t' || c >= ' ').collect();
+        let _ = writeln!(out, "{}{}", indent, line);
+    }
+}
+
+fn yaml_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::{Framework, Parsed};
+    use std::time::Duration;
+
+    fn case(name: &str, passed: bool, skipped: bool, message: &str) -> CaseResult {
+        CaseResult {
+            name: name.to_string(),
+            passed,
+            skipped,
+            duration: Some(Duration::from_millis(10)),
+            message: message.to_string(),
+        }
+    }
+
+    fn result(name: &str, code: Option<i32>, parsed: Option<Parsed>) -> TestResult {
+        TestResult {
+            name: name.to_string(),
+            passed: code == Some(0),
+            code,
+            duration: Duration::from_millis(250),
+            stdout: String::new(),
+            stderr: String::new(),
+            parsed,
+        }
+    }
+
+    //没认出框架的程序、GoogleTest、只列出失败用例的Catch2、被信号杀死的程序
+    fn results() -> Vec<TestResult> {
+        let mut plain = result("a<b>", Some(0), None);
+        plain.stdout = "hi & \"bye\"\n".to_string();
+        let gtest = result(
+            "math",
+            Some(1),
+            Some(Parsed {
+                framework: Framework::GoogleTest,
+                cases: vec![
+                    case("Add.Works", true, false, ""),
+                    case(
+                        "Div.Zero",
+                        false,
+                        false,
+                        "a.cpp:3: Failure\nExpected: 1 < 2",
+                    ),
+                    case("Skip.Me", true, true, ""),
+                ],
+                total: 3,
+                failed: 1,
+            }),
+        );
+        let mut catch2 = result(
+            "catchy",
+            Some(2),
+            Some(Parsed {
+                framework: Framework::Catch2,
+                cases: vec![case("vector \"grows\"", false, false, "x.cpp:5: FAILED")],
+                total: 4,
+                failed: 2,
+            }),
+        );
+        catch2.stderr = "bad\x01byte\n".to_string();
+        let crash = result("crash", None, None);
+        vec![plain, gtest, catch2, crash]
+    }
+
+    #[test]
+    fn junit_counts_match_written_testcases() {
+        let expected = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="sm" tests="6" failures="3" time="1.000">
+  <testsuite name="a&lt;b&gt;" tests="1" failures="0" skipped="0" time="0.250">
+    <testcase name="a&lt;b&gt;" classname="a&lt;b&gt;" time="0.250"/>
+    <system-out>hi &amp; &quot;bye&quot;
+</system-out>
+  </testsuite>
+  <testsuite name="math" tests="3" failures="1" skipped="1" time="0.250">
+    <testcase name="Add.Works" classname="math" time="0.010"/>
+    <testcase name="Div.Zero" classname="math" time="0.010">
+      <failure message="a.cpp:3: Failure">a.cpp:3: Failure
+Expected: 1 &lt; 2</failure>
+    </testcase>
+    <testcase name="Skip.Me" classname="math" time="0.010">
+      <skipped/>
+    </testcase>
+  </testsuite>
+  <testsuite name="catchy" tests="1" failures="1" skipped="0" time="0.250">
+    <testcase name="vector &quot;grows&quot;" classname="catchy" time="0.010">
+      <failure message="x.cpp:5: FAILED">x.cpp:5: FAILED</failure>
+    </testcase>
+    <system-err>badbyte
+</system-err>
+  </testsuite>
+  <testsuite name="crash" tests="1" failures="1" skipped="0" time="0.250">
+    <testcase name="crash" classname="crash" time="0.250">
+      <failure message="terminated without an exit code">terminated without an exit code</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#;
+        assert_eq!(junit(&results()), expected);
+    }
+
+    #[test]
+    fn tap_writes_a_yaml_block_per_program() {
+        let expected = r#"TAP version 13
+1..4
+ok 1 - a<b>
+  ---
+  duration_ms: 250
+  exit_code: 0
+  stdout: |
+    hi & "bye"
+  ...
+not ok 2 - math
+  ---
+  duration_ms: 250
+  exit_code: 1
+  framework: googletest
+  tests: 3
+  failed: 1
+  failures:
+    - name: "Div.Zero"
+      message: |
+        a.cpp:3: Failure
+        Expected: 1 < 2
+  ...
+not ok 3 - catchy
+  ---
+  duration_ms: 250
+  exit_code: 2
+  framework: catch2
+  tests: 4
+  failed: 2
+  failures:
+    - name: "vector \"grows\""
+      message: |
+        x.cpp:5: FAILED
+  stderr: |
+    badbyte
+  ...
+not ok 4 - crash
+  ---
+  duration_ms: 250
+  exit_code: ~
+  ...
+"#;
+        assert_eq!(tap(&results()), expected);
+    }
+}
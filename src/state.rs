@@ -0,0 +1,103 @@
+/*
+构建数据库，持久化保存在.sm/state.toml里面，
+记录每个编译单元的源文件摘要、完整命令和生成的目标文件，用于增量编译
+*/
+
+use crate::depfile::deps_are_fresh;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//单个编译单元的记录
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct UnitRecord {
+    //源文件内容的md5
+    pub hash: String,
+    //完整的编译命令
+    pub command: String,
+    //生成的目标文件
+    pub object: String,
+    //编译器报告的头文件依赖及其md5
+    #[serde(default)]
+    pub deps: BTreeMap<String, String>,
+}
+
+//打包、链接这类步骤的记录
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct StepRecord {
+    pub command: String,
+    //所有输入文件摘要的摘要
+    pub inputs: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct BuildState {
+    //键为源文件相对于项目根目录的路径
+    #[serde(default)]
+    pub units: BTreeMap<String, UnitRecord>,
+    //键为步骤名，比如lib、bin
+    #[serde(default)]
+    pub steps: BTreeMap<String, StepRecord>,
+}
+
+impl BuildState {
+    //读取构建数据库，不存在或者损坏时当作全新构建
+    pub fn load(path: &Path) -> BuildState {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_default(),
+            Err(_) => BuildState::default(),
+        }
+    }
+    //写回构建数据库，失败了也只是下次多编译一些，所以只提示不中断
+    pub fn save(&self, path: &Path) {
+        let content = match toml::to_string(self) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Can't serialize build state: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = fs::write(path, content) {
+            eprintln!("Can't write {}: {}", path.display(), e);
+        }
+    }
+    //判断编译单元是否需要重新编译，头文件依赖以上次记录的为准
+    pub fn unit_is_fresh(&self, src: &str, record: &UnitRecord) -> bool {
+        match self.units.get(src) {
+            Some(old) => {
+                old.hash == record.hash
+                    && old.command == record.command
+                    && old.object == record.object
+                    && Path::new(&record.object).is_file()
+                    && deps_are_fresh(&old.deps)
+            }
+            None => false,
+        }
+    }
+    //判断步骤是否需要重新执行
+    pub fn step_is_fresh(&self, name: &str, record: &StepRecord, output: &Path) -> bool {
+        match self.steps.get(name) {
+            Some(old) => {
+                old.command == record.command && old.inputs == record.inputs && output.is_file()
+            }
+            None => false,
+        }
+    }
+}
+
+//计算文件内容的md5
+pub fn hash_file(p: &Path) -> Option<String> {
+    let content = fs::read(p).ok()?;
+    Some(format!("{:x}", md5::compute(content)))
+}
+
+//计算一组文件的总摘要，任何一个文件缺失都返回None
+pub fn hash_files(files: &[PathBuf]) -> Option<String> {
+    let mut all = String::new();
+    for f in files {
+        all.push_str(&hash_file(f)?);
+        all.push('\n');
+    }
+    Some(format!("{:x}", md5::compute(all)))
+}
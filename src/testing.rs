@@ -0,0 +1,125 @@
+/*
+sm test的运行器，并行运行构建好的测试程序，
+退出码为0就算通过，最后按测试的顺序汇总结果
+*/
+
+use crate::command::{print_output, Verbosity};
+use crate::frameworks::{self, Parsed};
+use ansi_rgb::{green, red, Background};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//一个需要运行的测试
+pub struct TestCase {
+    pub name: String,
+    pub binary: PathBuf,
+    pub args: Vec<String>,
+}
+
+//测试的运行结果
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    //被信号杀死或者没能启动时为None
+    pub code: Option<i32>,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+    //认出了测试框架时，每个用例的结果
+    pub parsed: Option<Parsed>,
+}
+
+//最多同时运行jobs个测试，lib_path为运行时的LD_LIBRARY_PATH
+pub fn run_tests(
+    cases: &[TestCase],
+    jobs: usize,
+    lib_path: &OsString,
+    verbosity: Verbosity,
+) -> Vec<TestResult> {
+    let mut results: Vec<Option<TestResult>> = cases.iter().map(|_| None).collect();
+    let mut next = 0;
+    let mut running = 0;
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        loop {
+            while running < jobs.max(1) && next < cases.len() {
+                let case = &cases[next];
+                let i = next;
+                next += 1;
+                running += 1;
+                let tx = tx.clone();
+                scope.spawn(move || {
+                    let _ = tx.send((i, run_one(case, lib_path)));
+                });
+            }
+            if running == 0 {
+                break;
+            }
+            //完成一个打印一个，失败的测试把输出一起打印出来
+            let (i, result) = rx.recv().unwrap();
+            running -= 1;
+            if verbosity > Verbosity::Quiet || !result.passed {
+                let status = if result.passed {
+                    "ok".bg(green())
+                } else {
+                    "FAILED".bg(red())
+                };
+                let code = match (result.passed, result.code) {
+                    (true, _) => String::new(),
+                    (false, Some(c)) => format!("exit code {}, ", c),
+                    (false, None) => "no exit code, ".to_string(),
+                };
+                println!(
+                    "test {} ... {} ({}{:.2}s)",
+                    result.name,
+                    status,
+                    code,
+                    result.duration.as_secs_f64()
+                );
+            }
+            if !result.passed || verbosity == Verbosity::Verbose {
+                print_output(&result.stdout);
+                print_output(&result.stderr);
+            }
+            results[i] = Some(result);
+        }
+    });
+    results.into_iter().flatten().collect()
+}
+
+//运行一个测试程序，工作目录为项目根目录
+fn run_one(case: &TestCase, lib_path: &OsString) -> TestResult {
+    let start = Instant::now();
+    let output = Command::new(&case.binary)
+        .args(&case.args)
+        .env("LD_LIBRARY_PATH", lib_path)
+        .output();
+    let duration = start.elapsed();
+    match output {
+        Ok(o) => {
+            let stdout = String::from_utf8_lossy(&o.stdout).into_owned();
+            TestResult {
+                name: case.name.clone(),
+                passed: o.status.success(),
+                code: o.status.code(),
+                duration,
+                parsed: frameworks::parse(&stdout),
+                stdout,
+                stderr: String::from_utf8_lossy(&o.stderr).into_owned(),
+            }
+        }
+        Err(e) => TestResult {
+            name: case.name.clone(),
+            passed: false,
+            code: None,
+            duration,
+            stdout: String::new(),
+            stderr: format!("Excuting {} Failed: {}\n", case.binary.display(), e),
+            parsed: None,
+        },
+    }
+}
@@ -0,0 +1,209 @@
+/*
+实际使用的编译器和打包工具，可以来自配置文件、环境变量或者自动查找，
+编译器的家族和版本通过运行它来确定
+*/
+
+use crate::command::OneLineCommand;
+use crate::config::{Complier, CrossToolchain};
+use crate::error::{SmError, SmResult};
+use crate::probe::{self, ProbeCache};
+use std::env;
+use std::fmt;
+use std::path::Path;
+
+//编译器家族
+#[derive(Clone, Copy, PartialEq)]
+pub enum Family {
+    Gcc,
+    Clang,
+    Unknown,
+}
+
+impl Family {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Family::Gcc => "gcc",
+            Family::Clang => "clang",
+            Family::Unknown => "unknown",
+        }
+    }
+    pub fn from_name(name: &str) -> Family {
+        match name {
+            "gcc" => Family::Gcc,
+            "clang" => Family::Clang,
+            _ => Family::Unknown,
+        }
+    }
+}
+
+//一个外部工具，比如"ccache g++"拆成程序ccache和参数g++
+#[derive(Clone)]
+pub struct Tool {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl Tool {
+    pub fn parse(s: &str) -> Tool {
+        //整个字符串就是一个存在的文件时，路径里可以有空格
+        if Path::new(s).is_file() {
+            return Tool {
+                program: s.to_string(),
+                args: Vec::new(),
+            };
+        }
+        let mut words = s.split_whitespace().map(|w| w.to_string());
+        Tool {
+            program: words.next().unwrap_or_default(),
+            args: words.collect(),
+        }
+    }
+    //以这个工具开头的命令
+    pub fn command(&self) -> OneLineCommand {
+        let mut cmd = OneLineCommand::new(&self.program);
+        cmd.args(&self.args);
+        cmd
+    }
+    //真正的编译器名字，也就是最后一个词
+    fn last_mut(&mut self) -> &mut String {
+        match self.args.last_mut() {
+            Some(a) => a,
+            None => &mut self.program,
+        }
+    }
+}
+
+impl fmt::Display for Tool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for a in &self.args {
+            write!(f, " {}", a)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Toolchain {
+    pub cxx: Tool,
+    pub cc: Tool,
+    pub ar: Tool,
+    pub family: Family,
+    pub version: String,
+    //检查过的C++标准参数，比如-std=c++17
+    pub std_flag: String,
+    //交叉编译时编译和链接都要添加的参数
+    pub flags: Vec<String>,
+    //交叉编译时只在链接时添加的参数
+    pub link_flags: Vec<String>,
+}
+
+impl Toolchain {
+    //交叉编译工具链里写了的优先，其次是环境变量CXX、CC、AR，再其次是配置文件，
+    //都没有时自动查找
+    pub fn detect(
+        complier: &Complier,
+        cross: Option<&CrossToolchain>,
+        sm_dir: &Path,
+    ) -> SmResult<Toolchain> {
+        let cross = cross.cloned().unwrap_or_default();
+        let triple = &cross.target;
+        //只写了目标平台时，使用aarch64-linux-gnu-g++这样的编译器
+        let cross_cxx = match (cross.cxx.is_empty(), triple.is_empty()) {
+            (false, _) => Some(cross.cxx.clone()),
+            (true, false) => Some(format!("{}-g++", triple)),
+            (true, true) => None,
+        };
+        let cxx = match cross_cxx.clone().or_else(|| setting("CXX", &complier.cxx)) {
+            Some(c) => Tool::parse(&c),
+            None => match ["c++", "g++", "clang++"]
+                .iter()
+                .find(|c| probe::find_program(c).is_some())
+            {
+                Some(c) => Tool::parse(c),
+                None => {
+                    return Err(SmError::config(
+                        "complier.cxx",
+                        "No C++ complier found, please set cxx or CXX".to_string(),
+                    ))
+                }
+            },
+        };
+        if probe::find_program(&cxx.program).is_none() {
+            let field = if cross_cxx.is_some() {
+                "toolchain.cxx"
+            } else {
+                "complier.cxx"
+            };
+            return Err(SmError::config(
+                field,
+                format!("Can't find C++ complier {}", cxx),
+            ));
+        }
+        let mut cache = ProbeCache::load(sm_dir);
+        let (family, version) = probe::identify(&mut cache, &cxx)?;
+        let std_flag = probe::cxx_std_flag(&mut cache, &cxx, &complier.std)?;
+        //没有指定C编译器时使用和C++编译器配套的那个，交叉编译时不看环境变量
+        let cc = if !cross.cc.is_empty() {
+            Tool::parse(&cross.cc)
+        } else {
+            match setting("CC", &complier.cc).filter(|_| cross_cxx.is_none()) {
+                Some(c) => Tool::parse(&c),
+                None => {
+                    let mut cc = cxx.clone();
+                    let name = cc.last_mut();
+                    *name = c_name(name, family);
+                    cc
+                }
+            }
+        };
+        let cross_ar = format!("{}-ar", triple);
+        let ar = if !cross.ar.is_empty() {
+            cross.ar.clone()
+        } else if !triple.is_empty() && probe::find_program(&cross_ar).is_some() {
+            cross_ar
+        } else {
+            setting("AR", &complier.ar).unwrap_or_else(|| "ar".to_string())
+        };
+        //clang本身就是交叉编译器，用--target指定目标平台
+        let mut flags: Vec<String> = Vec::new();
+        if family == Family::Clang && !triple.is_empty() {
+            flags.push(format!("--target={}", triple));
+        }
+        if !cross.sysroot.is_empty() {
+            flags.push(format!("--sysroot={}", cross.sysroot));
+        }
+        flags.extend(cross.flags);
+        Ok(Toolchain {
+            cxx,
+            cc,
+            ar: Tool::parse(&ar),
+            family,
+            version,
+            std_flag,
+            flags,
+            link_flags: cross.link_flags,
+        })
+    }
+}
+
+//读取设置，环境变量优先，都为空时返回None
+fn setting(var: &str, configured: &str) -> Option<String> {
+    match env::var(var) {
+        Ok(v) if !v.trim().is_empty() => Some(v),
+        _ if !configured.trim().is_empty() => Some(configured.to_string()),
+        _ => None,
+    }
+}
+
+//根据C++编译器的名字求出C编译器的名字，比如g++-13对应gcc-13
+fn c_name(cxx: &str, family: Family) -> String {
+    for (from, to) in [("clang++", "clang"), ("g++", "gcc"), ("c++", "cc")] {
+        if let Some(i) = cxx.rfind(from) {
+            return format!("{}{}{}", &cxx[..i], to, &cxx[i + from.len()..]);
+        }
+    }
+    match family {
+        Family::Clang => "clang".to_string(),
+        _ => "gcc".to_string(),
+    }
+}